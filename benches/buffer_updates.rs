@@ -0,0 +1,98 @@
+//! Compares the work done on every keystroke before and after the status bar
+//! updates were made incremental, on a large generated file.
+//!
+//! Needs a display to initialize GTK:
+//!
+//! ```sh
+//! cargo bench --bench buffer_updates
+//! ```
+
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use gtk4::prelude::*;
+
+/// Lines in the generated file, about 10 MB of text
+const LINES: usize = 200_000;
+/// Keystrokes typed for each measurement
+const KEYSTROKES: u32 = 50;
+
+fn generate_text() -> String {
+    (0..LINES)
+        .map(|i| format!("{i:>8} the quick brown fox jumps over the lazy dog, déjà vu\n"))
+        .collect()
+}
+
+/// What the `changed` and `mark-set` handlers used to do: copy out the whole
+/// text to count its characters, copy it again into the model, then copy and
+/// hash it once more to tell if the buffer was dirty
+fn full_copy_update(buffer: &sourceview5::Buffer) -> (i32, u64) {
+    let text = buffer.text(&buffer.start_iter(), &buffer.end_iter(), false);
+    let char_count = text.as_str().chars().count() as i32;
+    let _copy = text.to_string();
+
+    let content = buffer.text(&buffer.start_iter(), &buffer.end_iter(), true);
+    let mut hasher = std::hash::DefaultHasher::new();
+    content.hash(&mut hasher);
+
+    let cursor = buffer.iter_at_mark(&buffer.get_insert());
+    let _ = (cursor.line(), cursor.line_offset());
+    (char_count, hasher.finish())
+}
+
+/// What they do now: read the cursor position and the counts the buffer
+/// keeps anyway, dirty state comes from the modified flag
+fn incremental_update(buffer: &sourceview5::Buffer) -> (i32, bool) {
+    let cursor = buffer.iter_at_mark(&buffer.get_insert());
+    let _ = (cursor.line(), cursor.line_offset());
+    (buffer.char_count(), buffer.is_modified())
+}
+
+/// Time typing `KEYSTROKES` characters into the middle of the buffer, running
+/// `update` after each one like the signal handlers do
+fn measure<T>(
+    buffer: &sourceview5::Buffer,
+    update: impl Fn(&sourceview5::Buffer) -> T,
+) -> Duration {
+    let mut iter = buffer.iter_at_line((LINES / 2) as i32).unwrap();
+    buffer.place_cursor(&iter);
+
+    let start = Instant::now();
+    for _ in 0..KEYSTROKES {
+        buffer.insert_at_cursor("x");
+        std::hint::black_box(update(buffer));
+    }
+    let elapsed = start.elapsed();
+
+    // Put the text back for the next measurement
+    iter = buffer.iter_at_mark(&buffer.get_insert());
+    let mut typed = iter;
+    typed.backward_chars(KEYSTROKES as i32);
+    buffer.delete(&mut typed, &mut iter);
+    elapsed
+}
+
+fn main() {
+    gtk4::init().expect("GTK needs a display to run this benchmark");
+
+    let text = generate_text();
+    let buffer = sourceview5::Buffer::new(None);
+    buffer.set_text(&text);
+    buffer.set_modified(false);
+    println!(
+        "{} lines, {:.1} MB, {} keystrokes each",
+        LINES,
+        text.len() as f64 / 1_000_000.0,
+        KEYSTROKES
+    );
+
+    let full = measure(&buffer, full_copy_update);
+    let incremental = measure(&buffer, incremental_update);
+    let per_key = |total: Duration| total / KEYSTROKES;
+    println!("full copy:   {:>10.2?} per keystroke", per_key(full));
+    println!("incremental: {:>10.2?} per keystroke", per_key(incremental));
+    println!(
+        "speedup:     {:>10.0}x",
+        full.as_secs_f64() / incremental.as_secs_f64().max(f64::EPSILON)
+    );
+}
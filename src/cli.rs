@@ -0,0 +1,199 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use gtk4::gio;
+
+/// A file to open, with where to put the cursor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OpenRequest {
+    pub(crate) path: PathBuf,
+    /// 1-based line and column to move the cursor to
+    pub(crate) position: Option<(i32, i32)>,
+    /// Invocation to keep waiting until the file's window is closed, for `--wait`
+    pub(crate) waiter: Option<gio::ApplicationCommandLine>,
+}
+
+/// Parsed command-line arguments
+///
+/// Supported forms, for every file argument:
+/// - `file`
+/// - `file:line` and `file:line:column`, as printed by compilers and grep
+/// - `+line file` and `+line:column file`, as used by `$EDITOR` callers
+/// - `-`, to read a document from standard input
+///
+/// `--wait` (or `-w`) keeps the invoking process running until every file it
+/// opened has its window closed, so Enigmata can be used as `$EDITOR`.
+#[derive(Debug, Default)]
+pub(crate) struct CommandLine {
+    /// Files to open, each in its own window
+    pub(crate) files: Vec<OpenRequest>,
+    /// Open what's piped into standard input in an untitled window
+    pub(crate) stdin: bool,
+    pub(crate) wait: bool,
+}
+
+/// Parse `line` or `line:column`
+fn parse_position(position: &str) -> Option<(i32, i32)> {
+    match position.split_once(':') {
+        Some((line, column)) => Some((line.parse().ok()?, column.parse().ok()?)),
+        None => Some((position.parse().ok()?, 1)),
+    }
+}
+
+/// Split `file:line` or `file:line:column` into the file and position
+fn split_file_position(arg: &str) -> Option<(&str, (i32, i32))> {
+    // grep -n style output may leave a trailing colon
+    let arg = arg.trim_end_matches(':');
+    let (rest, last) = arg.rsplit_once(':')?;
+    let last: i32 = last.parse().ok()?;
+    if let Some((file, line)) = rest.rsplit_once(':') {
+        if let Ok(line) = line.parse() {
+            return Some((file, (line, last)));
+        }
+    }
+    Some((rest, (last, 1)))
+}
+
+impl CommandLine {
+    /// Parse the arguments after the program name, resolving relative paths against `cwd`
+    pub(crate) fn parse(args: &[OsString], cwd: &Path) -> Self {
+        let mut command_line = Self::default();
+        let mut next_position = None;
+        let mut options_ended = false;
+
+        for arg in args {
+            let arg_str = arg.to_str();
+
+            if !options_ended {
+                match arg_str {
+                    Some("--") => {
+                        options_ended = true;
+                        continue;
+                    }
+                    Some("-") => {
+                        command_line.stdin = true;
+                        continue;
+                    }
+                    Some("--wait" | "-w") => {
+                        command_line.wait = true;
+                        continue;
+                    }
+                    Some(plus) if plus.starts_with('+') => {
+                        match parse_position(&plus[1..]) {
+                            Some(position) => next_position = Some(position),
+                            None => println!("Ignoring invalid position: {}", plus),
+                        }
+                        continue;
+                    }
+                    Some(option) if option.starts_with("--") => {
+                        println!("Ignoring unknown option: {}", option);
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+
+            let as_is = cwd.join(arg);
+            let (path, position) = match arg_str.and_then(split_file_position) {
+                // A file with a colon in its name wins over a position
+                Some((file, position)) if !as_is.exists() => (cwd.join(file), Some(position)),
+                _ => (as_is, None),
+            };
+
+            command_line.files.push(OpenRequest {
+                path,
+                position: next_position.take().or(position),
+                waiter: None,
+            });
+        }
+
+        command_line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_io::tests::TempDir;
+
+    fn parse(args: &[&str], cwd: &Path) -> CommandLine {
+        let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        CommandLine::parse(&args, cwd)
+    }
+
+    fn opened(command_line: &CommandLine) -> Vec<(PathBuf, Option<(i32, i32)>)> {
+        command_line
+            .files
+            .iter()
+            .map(|request| (request.path.clone(), request.position))
+            .collect()
+    }
+
+    #[test]
+    fn splits_file_positions() {
+        assert_eq!(split_file_position("a.rs:12"), Some(("a.rs", (12, 1))));
+        assert_eq!(split_file_position("a.rs:12:5"), Some(("a.rs", (12, 5))));
+        assert_eq!(split_file_position("a.rs:12:"), Some(("a.rs", (12, 1))));
+        assert_eq!(split_file_position("a:b.rs:3"), Some(("a:b.rs", (3, 1))));
+        assert_eq!(split_file_position("a.rs"), None);
+        assert_eq!(split_file_position("a.rs:x"), None);
+    }
+
+    #[test]
+    fn parses_files_and_positions() {
+        let cwd = Path::new("/work");
+        let command_line = parse(&["a.rs", "b.rs:4:2", "+7", "c.rs", "+3:9", "d.rs:1"], cwd);
+        assert_eq!(
+            opened(&command_line),
+            vec![
+                (cwd.join("a.rs"), None),
+                (cwd.join("b.rs"), Some((4, 2))),
+                (cwd.join("c.rs"), Some((7, 1))),
+                (cwd.join("d.rs"), Some((3, 9))),
+            ]
+        );
+        assert!(!command_line.stdin);
+        assert!(!command_line.wait);
+    }
+
+    #[test]
+    fn parses_options() {
+        let cwd = Path::new("/work");
+        assert!(parse(&["--wait", "a.rs"], cwd).wait);
+        assert!(parse(&["-w", "a.rs"], cwd).wait);
+        assert!(parse(&["-"], cwd).stdin);
+
+        let command_line = parse(&["--unknown", "+x", "a.rs"], cwd);
+        assert_eq!(opened(&command_line), vec![(cwd.join("a.rs"), None)]);
+    }
+
+    #[test]
+    fn treats_everything_after_double_dash_as_files() {
+        let cwd = Path::new("/work");
+        let command_line = parse(&["--", "-", "--wait", "+2"], cwd);
+        assert_eq!(
+            opened(&command_line),
+            vec![
+                (cwd.join("-"), None),
+                (cwd.join("--wait"), None),
+                (cwd.join("+2"), None),
+            ]
+        );
+        assert!(!command_line.stdin);
+        assert!(!command_line.wait);
+    }
+
+    #[test]
+    fn prefers_existing_file_with_colon() {
+        let temp = TempDir::new("cli-colon");
+        std::fs::write(temp.0.join("notes:2"), "").unwrap();
+        let command_line = parse(&["notes:2", "other:2"], &temp.0);
+        assert_eq!(
+            opened(&command_line),
+            vec![
+                (temp.0.join("notes:2"), None),
+                (temp.0.join("other"), Some((2, 1))),
+            ]
+        );
+    }
+}
@@ -0,0 +1,465 @@
+use std::borrow::Cow;
+
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+/// How many bytes to look at when guessing if a file is UTF-16 without a BOM
+const UTF16_SNIFF_LEN: usize = 4096;
+/// How many bytes to look at when guessing if a file is binary
+const BINARY_SNIFF_LEN: usize = 8192;
+
+/// Encodings offered when reopening or saving with a different encoding, as
+/// an ID for [`FileFormat::from_id`] and a name for the menu
+pub(crate) const ENCODINGS: &[(&str, &str)] = &[
+    ("UTF-8", "Unicode (UTF-8)"),
+    ("UTF-8+BOM", "Unicode (UTF-8 with BOM)"),
+    ("UTF-16LE+BOM", "Unicode (UTF-16 LE)"),
+    ("UTF-16BE+BOM", "Unicode (UTF-16 BE)"),
+    ("windows-1252", "Western (Windows-1252)"),
+    ("ISO-8859-15", "Western (ISO-8859-15)"),
+    ("windows-1250", "Central European (Windows-1250)"),
+    ("ISO-8859-2", "Central European (ISO-8859-2)"),
+    ("windows-1251", "Cyrillic (Windows-1251)"),
+    ("KOI8-R", "Cyrillic (KOI8-R)"),
+    ("windows-1253", "Greek (Windows-1253)"),
+    ("windows-1254", "Turkish (Windows-1254)"),
+    ("windows-1255", "Hebrew (Windows-1255)"),
+    ("windows-1256", "Arabic (Windows-1256)"),
+    ("windows-874", "Thai (Windows-874)"),
+    ("windows-1258", "Vietnamese (Windows-1258)"),
+    ("Shift_JIS", "Japanese (Shift_JIS)"),
+    ("EUC-JP", "Japanese (EUC-JP)"),
+    ("GBK", "Chinese Simplified (GBK)"),
+    ("gb18030", "Chinese Simplified (GB18030)"),
+    ("Big5", "Chinese Traditional (Big5)"),
+    ("EUC-KR", "Korean (EUC-KR)"),
+];
+
+/// What ends lines in a file
+///
+/// The buffer always uses `\n`, files get their own line ending back on save.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LineEnding {
+    /// `\n`, used on Linux and macOS
+    #[default]
+    Lf,
+    /// `\r\n`, used on Windows
+    CrLf,
+    /// `\r`, used on classic Mac OS
+    Cr,
+}
+
+impl LineEnding {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Lf => "\n",
+            Self::CrLf => "\r\n",
+            Self::Cr => "\r",
+        }
+    }
+
+    /// Name of the line ending, also used as its action target
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Lf => "LF",
+            Self::CrLf => "CRLF",
+            Self::Cr => "CR",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        [Self::Lf, Self::CrLf, Self::Cr]
+            .into_iter()
+            .find(|ending| ending.name() == name)
+    }
+}
+
+/// How a document's text is stored on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileFormat {
+    pub(crate) encoding: &'static Encoding,
+    /// Whether the file starts with a byte order mark
+    pub(crate) bom: bool,
+    pub(crate) line_ending: LineEnding,
+}
+
+impl Default for FileFormat {
+    fn default() -> Self {
+        Self {
+            encoding: UTF_8,
+            bom: false,
+            line_ending: LineEnding::default(),
+        }
+    }
+}
+
+/// Text decoded from a file's bytes
+#[derive(Debug)]
+pub(crate) struct DecodedText {
+    pub(crate) text: String,
+    pub(crate) format: FileFormat,
+    /// Some bytes weren't valid in the encoding and were replaced with U+FFFD,
+    /// so saving won't give back the original file
+    pub(crate) lossy: bool,
+    /// The file used more than one kind of line ending, saving will only use
+    /// the most common one
+    pub(crate) mixed_line_endings: bool,
+    /// How many bytes the text was decoded from, including the BOM
+    pub(crate) byte_len: usize,
+}
+
+impl FileFormat {
+    /// Parse an ID from [`ENCODINGS`], an encoding label with an optional
+    /// `+BOM` suffix, into a format with LF line endings
+    pub(crate) fn from_id(id: &str) -> Option<Self> {
+        let (label, bom) = match id.strip_suffix("+BOM") {
+            Some(label) => (label, true),
+            None => (id, false),
+        };
+        let encoding = Encoding::for_label(label.as_bytes())?;
+        // Only Unicode encodings have a byte order mark
+        if bom && ![UTF_8, UTF_16LE, UTF_16BE].contains(&encoding) {
+            return None;
+        }
+        Some(Self {
+            encoding,
+            bom,
+            ..Self::default()
+        })
+    }
+
+    /// Name of the encoding, for the status bar
+    pub(crate) fn name(&self) -> String {
+        match (self.encoding == UTF_8, self.bom) {
+            (true, true) => "UTF-8 with BOM".to_string(),
+            _ => self.encoding.name().to_string(),
+        }
+    }
+
+    fn bom_bytes(&self) -> &'static [u8] {
+        if !self.bom {
+            &[]
+        } else if self.encoding == UTF_8 {
+            b"\xEF\xBB\xBF"
+        } else if self.encoding == UTF_16LE {
+            b"\xFF\xFE"
+        } else if self.encoding == UTF_16BE {
+            b"\xFE\xFF"
+        } else {
+            &[]
+        }
+    }
+
+    /// Encode `text` in this format
+    ///
+    /// Also returns whether some characters couldn't be represented in the
+    /// encoding, those are written as HTML character references instead.
+    pub(crate) fn encode(&self, text: &str) -> (Vec<u8>, bool) {
+        // Pasted text may bring its own line endings along
+        let (normalized, _, _) = normalize_line_endings(text);
+        let text = match self.line_ending {
+            LineEnding::Lf => normalized,
+            ending => Cow::Owned(normalized.replace('\n', ending.as_str())),
+        };
+        let text = text.as_ref();
+
+        let mut bytes = self.bom_bytes().to_vec();
+        // encoding_rs only decodes UTF-16, following the Encoding Standard
+        if self.encoding == UTF_16LE {
+            bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+            return (bytes, false);
+        }
+        if self.encoding == UTF_16BE {
+            bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+            return (bytes, false);
+        }
+        let (encoded, _, unmappable) = self.encoding.encode(text);
+        bytes.extend_from_slice(&encoded);
+        (bytes, unmappable)
+    }
+}
+
+/// Decode a file's bytes, with `encoding` or the detected one if `None`
+pub(crate) fn decode(bytes: &[u8], encoding: Option<&'static Encoding>) -> DecodedText {
+    let bom = Encoding::for_bom(bytes);
+    let (encoding, bom_len) = match (encoding, bom) {
+        (Some(encoding), Some((bom_encoding, bom_len))) if bom_encoding == encoding => {
+            (encoding, bom_len)
+        }
+        (Some(encoding), _) => (encoding, 0),
+        (None, Some((bom_encoding, bom_len))) => (bom_encoding, bom_len),
+        (None, None) => (detect_encoding(bytes), 0),
+    };
+
+    let (text, lossy) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+    let (text, line_ending, mixed_line_endings) = normalize_line_endings(&text);
+    DecodedText {
+        text: text.into_owned(),
+        format: FileFormat {
+            encoding,
+            bom: bom_len > 0,
+            line_ending,
+        },
+        lossy,
+        mixed_line_endings,
+        byte_len: bytes.len(),
+    }
+}
+
+/// Turn every line ending in `text` into `\n`
+///
+/// Also returns the most common line ending, and whether there was more than
+/// one kind.
+pub(crate) fn normalize_line_endings(text: &str) -> (Cow<'_, str>, LineEnding, bool) {
+    if !text.contains('\r') {
+        return (Cow::Borrowed(text), LineEnding::Lf, false);
+    }
+
+    let (mut lf, mut crlf, mut cr) = (0usize, 0usize, 0usize);
+    let mut normalized = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find(['\r', '\n']) {
+        normalized.push_str(&rest[..i]);
+        normalized.push('\n');
+        let after = &rest[i + 1..];
+        rest = match (rest.as_bytes()[i], after.starts_with('\n')) {
+            (b'\r', true) => {
+                crlf += 1;
+                &after[1..]
+            }
+            (b'\r', false) => {
+                cr += 1;
+                after
+            }
+            _ => {
+                lf += 1;
+                after
+            }
+        };
+    }
+    normalized.push_str(rest);
+
+    // Ties go to LF, then CRLF
+    let dominant = if crlf > lf && crlf >= cr {
+        LineEnding::CrLf
+    } else if cr > lf && cr > crlf {
+        LineEnding::Cr
+    } else {
+        LineEnding::Lf
+    };
+    let kinds = [lf, crlf, cr].iter().filter(|&&count| count > 0).count();
+    (Cow::Owned(normalized), dominant, kinds > 1)
+}
+
+/// Whether `bytes` look like binary data rather than text in any encoding
+///
+/// Text never has NUL bytes, unless it's UTF-16, and rarely has many control
+/// characters other than whitespace and escape sequences.
+pub(crate) fn is_binary(bytes: &[u8]) -> bool {
+    if Encoding::for_bom(bytes).is_some() || detect_utf16(bytes).is_some() {
+        return false;
+    }
+    let sample = &bytes[..bytes.len().min(BINARY_SNIFF_LEN)];
+    if sample.contains(&0) {
+        return true;
+    }
+    let control = sample
+        .iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+        .count();
+    control * 10 > sample.len()
+}
+
+/// Guess the encoding of text without a byte order mark
+fn detect_encoding(bytes: &[u8]) -> &'static Encoding {
+    // Mostly ASCII UTF-16 is also valid UTF-8, NUL bytes and all, so it has
+    // to be ruled out first
+    if let Some(encoding) = detect_utf16(bytes) {
+        return encoding;
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return UTF_8;
+    }
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(bytes, true);
+    detector.guess(None, false)
+}
+
+/// UTF-16 text in scripts using Latin letters has a NUL byte in every other
+/// position, which legacy encodings never have
+fn detect_utf16(bytes: &[u8]) -> Option<&'static Encoding> {
+    let sample = &bytes[..bytes.len().min(UTF16_SNIFF_LEN) & !1];
+    if sample.is_empty() {
+        return None;
+    }
+    let pairs = sample.len() / 2;
+    let zeros_at = |parity: usize| {
+        sample
+            .iter()
+            .skip(parity)
+            .step_by(2)
+            .filter(|&&b| b == 0)
+            .count()
+    };
+    let (even, odd) = (zeros_at(0), zeros_at(1));
+
+    if odd * 10 > pairs * 3 && even * 20 < pairs {
+        Some(UTF_16LE)
+    } else if even * 10 > pairs * 3 && odd * 20 < pairs {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16le(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    fn utf16be(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_be_bytes).collect()
+    }
+
+    #[test]
+    fn detects_utf8() {
+        let decoded = decode("héllo wörld\n".as_bytes(), None);
+        assert_eq!(decoded.format.encoding, UTF_8);
+        assert!(!decoded.format.bom);
+        assert_eq!(decoded.text, "héllo wörld\n");
+        assert!(!decoded.lossy);
+    }
+
+    #[test]
+    fn detects_utf8_with_bom() {
+        let decoded = decode(b"\xEF\xBB\xBFhello", None);
+        assert_eq!(decoded.format.encoding, UTF_8);
+        assert!(decoded.format.bom);
+        assert_eq!(decoded.text, "hello");
+    }
+
+    #[test]
+    fn detects_utf16le_without_bom() {
+        let decoded = decode(&utf16le("hello world\nsecond line\n"), None);
+        assert_eq!(decoded.format.encoding, UTF_16LE);
+        assert!(!decoded.format.bom);
+        assert_eq!(decoded.text, "hello world\nsecond line\n");
+    }
+
+    #[test]
+    fn detects_utf16be_without_bom() {
+        let decoded = decode(&utf16be("hello world\nsecond line\n"), None);
+        assert_eq!(decoded.format.encoding, UTF_16BE);
+        assert!(!decoded.format.bom);
+        assert_eq!(decoded.text, "hello world\nsecond line\n");
+    }
+
+    #[test]
+    fn detects_utf16le_with_bom() {
+        let mut bytes = b"\xFF\xFE".to_vec();
+        bytes.extend(utf16le("hello"));
+        let decoded = decode(&bytes, None);
+        assert_eq!(decoded.format.encoding, UTF_16LE);
+        assert!(decoded.format.bom);
+        assert_eq!(decoded.text, "hello");
+    }
+
+    #[test]
+    fn detects_utf16be_with_bom() {
+        let mut bytes = b"\xFE\xFF".to_vec();
+        bytes.extend(utf16be("hello"));
+        let decoded = decode(&bytes, None);
+        assert_eq!(decoded.format.encoding, UTF_16BE);
+        assert!(decoded.format.bom);
+        assert_eq!(decoded.text, "hello");
+    }
+
+    #[test]
+    fn falls_back_to_legacy_encoding() {
+        let bytes = b"Le caf\xe9 est tr\xe8s chaud, et la cr\xe8me br\xfbl\xe9e est d\xe9licieuse.";
+        let decoded = decode(bytes, None);
+        assert_eq!(decoded.format.encoding, encoding_rs::WINDOWS_1252);
+        assert_eq!(
+            decoded.text,
+            "Le café est très chaud, et la crème brûlée est délicieuse."
+        );
+        assert!(!decoded.lossy);
+    }
+
+    #[test]
+    fn utf16_round_trips() {
+        for id in ["UTF-16LE+BOM", "UTF-16BE+BOM"] {
+            let format = FileFormat::from_id(id).unwrap();
+            let (bytes, unmappable) = format.encode("hello\nwörld\n");
+            assert!(!unmappable);
+            let decoded = decode(&bytes, None);
+            assert_eq!(decoded.format, format);
+            assert_eq!(decoded.text, "hello\nwörld\n");
+        }
+    }
+
+    #[test]
+    fn normalizes_line_endings() {
+        assert_eq!(
+            normalize_line_endings("a\nb\n"),
+            (Cow::Borrowed("a\nb\n"), LineEnding::Lf, false)
+        );
+        let (text, ending, mixed) = normalize_line_endings("a\r\nb\r\nc");
+        assert_eq!(
+            (text.as_ref(), ending, mixed),
+            ("a\nb\nc", LineEnding::CrLf, false)
+        );
+        let (text, ending, mixed) = normalize_line_endings("a\rb\r");
+        assert_eq!(
+            (text.as_ref(), ending, mixed),
+            ("a\nb\n", LineEnding::Cr, false)
+        );
+    }
+
+    #[test]
+    fn picks_most_common_of_mixed_line_endings() {
+        let (text, ending, mixed) = normalize_line_endings("a\r\nb\r\nc\nd");
+        assert_eq!(
+            (text.as_ref(), ending, mixed),
+            ("a\nb\nc\nd", LineEnding::CrLf, true)
+        );
+        // Ties go to LF
+        let (_, ending, mixed) = normalize_line_endings("a\r\nb\nc");
+        assert_eq!((ending, mixed), (LineEnding::Lf, true));
+    }
+
+    #[test]
+    fn encodes_with_line_ending() {
+        let mut format = FileFormat::default();
+        for (ending, expected) in [
+            (LineEnding::Lf, "a\nb\nc\n"),
+            (LineEnding::CrLf, "a\r\nb\r\nc\r\n"),
+            (LineEnding::Cr, "a\rb\rc\r"),
+        ] {
+            format.line_ending = ending;
+            // Pasted line endings are converted too
+            let (bytes, _) = format.encode("a\nb\r\nc\r");
+            assert_eq!(bytes, expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn recognizes_binary_data() {
+        assert!(is_binary(b"\x7fELF\x02\x01\x01\x00\x00\x00"));
+        assert!(is_binary(&[0x01, 0x02, 0x03, 0x04, b'a', b'b', 0x05, 0x06]));
+        assert!(!is_binary(b""));
+        assert!(!is_binary(
+            b"plain text\twith tabs\r\n\x1b[1mand escapes\x1b[0m\n"
+        ));
+        assert!(!is_binary("h\u{e9}llo".as_bytes()));
+    }
+
+    #[test]
+    fn utf16_text_is_not_binary() {
+        assert!(!is_binary(&utf16le("hello world\n")));
+        assert!(!is_binary(&utf16be("hello world\n")));
+        assert!(!is_binary(b"\xFF\xFEh\x00i\x00"));
+    }
+}
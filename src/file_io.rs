@@ -0,0 +1,848 @@
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use gtk4::glib;
+
+use crate::file_format::DecodedText;
+use crate::settings::BackupSettings;
+
+/// Symlinks are followed at most this many times, like the kernel does
+const MAX_SYMLINK_DEPTH: usize = 40;
+/// Bytes read or written between progress updates and checks for cancelling
+const CHUNK_SIZE: usize = 1024 * 1024;
+/// Timestamp in the names of backups in the backup folder, which sorts
+/// oldest first. Later backups in the same second also get a counter.
+const BACKUP_TIMESTAMP_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
+/// Progress of a load or save running on another thread, through which the
+/// UI can also cancel it
+#[derive(Debug, Default)]
+pub(crate) struct Progress {
+    done: AtomicU64,
+    total: AtomicU64,
+    cancelled: AtomicBool,
+    /// A save in place started, which can't be stopped anymore
+    in_place: AtomicBool,
+}
+
+impl Progress {
+    /// How much is done, from 0 to 1
+    pub(crate) fn fraction(&self) -> f64 {
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0.0;
+        }
+        (self.done.load(Ordering::Relaxed) as f64 / total as f64).min(1.0)
+    }
+
+    /// Stop at the next chunk, the operation then fails with an error for
+    /// which [`FileError::is_cancelled`] is true
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether cancelling would still stop it
+    pub(crate) fn is_cancellable(&self) -> bool {
+        !self.in_place.load(Ordering::Relaxed)
+    }
+
+    fn start(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+        self.done.store(0, Ordering::Relaxed);
+    }
+
+    fn advance(&self, bytes: usize) {
+        self.done.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn check_cancelled(&self) -> std::io::Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            Err(std::io::Error::other(Cancelled))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Error source for a load or save cancelled through its [`Progress`]
+#[derive(Debug)]
+struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// What was being done to a file when it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FileOperation {
+    Load,
+    Save,
+    /// Keeping the previous contents before saving over a file
+    Backup,
+}
+
+/// A file couldn't be loaded or saved
+#[derive(Debug)]
+pub(crate) struct FileError {
+    pub(crate) operation: FileOperation,
+    pub(crate) path: PathBuf,
+    pub(crate) source: std::io::Error,
+}
+
+impl FileError {
+    pub(crate) fn new(operation: FileOperation, path: &Path, source: std::io::Error) -> Self {
+        Self {
+            operation,
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// Whether it was cancelled rather than failed
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.source
+            .get_ref()
+            .is_some_and(|source| source.is::<Cancelled>())
+    }
+
+    /// Why it failed, worded for the user
+    pub(crate) fn reason(&self) -> String {
+        match self.source.kind() {
+            ErrorKind::PermissionDenied => "Permission denied".to_string(),
+            ErrorKind::NotFound => match self.operation {
+                FileOperation::Load => "The file doesn't exist".to_string(),
+                FileOperation::Save | FileOperation::Backup => {
+                    "The folder doesn't exist".to_string()
+                }
+            },
+            ErrorKind::IsADirectory => "It's a folder, not a file".to_string(),
+            ErrorKind::StorageFull => "The disk is full".to_string(),
+            ErrorKind::QuotaExceeded => "The disk quota is exceeded".to_string(),
+            ErrorKind::ReadOnlyFilesystem => "The file system is read-only".to_string(),
+            ErrorKind::FileTooLarge => "The file is too large".to_string(),
+            _ => self.source.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self.operation {
+            FileOperation::Load => "open",
+            FileOperation::Save => "save",
+            FileOperation::Backup => "back up",
+        };
+        write!(
+            f,
+            "Could not {} {}: {}",
+            action,
+            self.path.display(),
+            self.reason()
+        )
+    }
+}
+
+impl std::error::Error for FileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Read the file at `path` as text, in `encoding` or the detected one if `None`
+pub(crate) fn load_file(
+    path: &Path,
+    encoding: Option<&'static encoding_rs::Encoding>,
+) -> Result<DecodedText, FileError> {
+    let content = read_file(path, &Progress::default())?;
+    Ok(crate::file_format::decode(&content, encoding))
+}
+
+/// A file read by [`load_document`]
+#[derive(Debug)]
+pub(crate) enum LoadedFile {
+    Text(DecodedText),
+    /// The file looks binary, so it wasn't decoded
+    Binary(Vec<u8>),
+}
+
+/// Read a file to edit it, in `encoding` or the detected one if `None`
+///
+/// Files that look binary are left as bytes, unless an encoding is given or
+/// `as_text` is set. Meant to run off the main thread for large files.
+pub(crate) fn load_document(
+    path: &Path,
+    encoding: Option<&'static encoding_rs::Encoding>,
+    as_text: bool,
+    progress: &Progress,
+) -> Result<LoadedFile, FileError> {
+    let content = read_file(path, progress)?;
+    if encoding.is_none() && !as_text && crate::file_format::is_binary(&content) {
+        return Ok(LoadedFile::Binary(content));
+    }
+    Ok(LoadedFile::Text(crate::file_format::decode(
+        &content, encoding,
+    )))
+}
+
+/// Read up to `max` bytes of what's in the file at `path` past `offset`, for
+/// a file that grew
+pub(crate) fn read_appended(path: &Path, offset: u64, max: u64) -> Result<Vec<u8>, FileError> {
+    let read = || {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut appended = Vec::new();
+        file.take(max).read_to_end(&mut appended)?;
+        Ok(appended)
+    };
+    read().map_err(|e| FileError::new(FileOperation::Load, path, e))
+}
+
+/// Read the bytes of the file at `path` in chunks, without decoding them
+fn read_file(path: &Path, progress: &Progress) -> Result<Vec<u8>, FileError> {
+    let error = |e| FileError::new(FileOperation::Load, path, e);
+    let mut file = File::open(path).map_err(error)?;
+    let len = file.metadata().map(|m| m.len()).unwrap_or_default();
+    progress.start(len);
+
+    let mut content = Vec::with_capacity(len as usize);
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        progress.check_cancelled().map_err(error)?;
+        match file.read(&mut chunk) {
+            Ok(0) => return Ok(content),
+            Ok(read) => {
+                content.extend_from_slice(&chunk[..read]);
+                progress.advance(read);
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(error(e)),
+        }
+    }
+}
+
+/// Write `content` to the file at `path`
+///
+/// Every save in the editor goes through here, including replacing across files.
+///
+/// The content is written to a temporary file next to the target, which then
+/// replaces it in one rename, so a crash never leaves a half-written file.
+/// Symlinks are followed so the link itself stays intact, and the mode, owner
+/// and extended attributes of the old file are kept.
+///
+/// With `backup` settings that are enabled, the previous contents are kept
+/// too.
+pub(crate) fn save_file(
+    path: &Path,
+    content: &[u8],
+    backup: Option<&BackupSettings>,
+) -> Result<(), FileError> {
+    save_file_with_progress(path, content, backup, &Progress::default())
+}
+
+/// [`save_file`], reporting progress and stopping if cancelled
+///
+/// A cancelled save leaves the file as it was. Only a save in place can't be
+/// stopped once it started, as that would leave half a file behind.
+pub(crate) fn save_file_with_progress(
+    path: &Path,
+    content: &[u8],
+    backup: Option<&BackupSettings>,
+    progress: &Progress,
+) -> Result<(), FileError> {
+    let error = |e| FileError::new(FileOperation::Save, path, e);
+    let target = resolve_symlinks(path).map_err(error)?;
+    let existing = std::fs::metadata(&target).ok();
+    let backup =
+        backup.filter(|backup| backup.enabled && existing.as_ref().is_some_and(|m| m.is_file()));
+
+    let in_place = |target: &Path| {
+        if let Some(backup) = backup {
+            make_backup(target, backup, BackupMethod::Copy)?;
+        }
+        progress.check_cancelled().map_err(error)?;
+        write_in_place(target, content, progress).map_err(error)
+    };
+
+    // Renaming over a hard link would split it from its other names
+    if existing.as_ref().is_some_and(|m| m.nlink() > 1) {
+        return in_place(&target);
+    }
+
+    match write_atomically(&target, content, existing.as_ref(), backup, progress) {
+        // Without write access to the directory there's no temporary file,
+        // writing in place is the only way left
+        Err(AtomicWriteError::TempFile(e))
+            if matches!(
+                e.kind(),
+                ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem
+            ) =>
+        {
+            in_place(&target)
+        }
+        Err(AtomicWriteError::TempFile(e) | AtomicWriteError::Write(e)) => Err(error(e)),
+        Err(AtomicWriteError::Backup(e)) => Err(e),
+        Ok(()) => Ok(()),
+    }
+}
+
+/// How a backup gets the previous contents of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BackupMethod {
+    /// Hard link the file, for when it's about to be replaced by a rename
+    /// and its contents are left alone
+    Link,
+    /// Copy the file, for when it's about to be overwritten in place
+    Copy,
+}
+
+/// Keep the current contents of `path` as a backup, before it's saved over
+fn make_backup(
+    path: &Path,
+    settings: &BackupSettings,
+    method: BackupMethod,
+) -> Result<(), FileError> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let backup_path = match &settings.directory {
+        None => path.with_file_name(format!("{}~", name)),
+        Some(dir) => {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| FileError::new(FileOperation::Backup, dir, e))?;
+            let prefix = backup_prefix(path);
+            let stamp = glib::DateTime::now_local()
+                .and_then(|now| now.format(BACKUP_TIMESTAMP_FORMAT))
+                .unwrap_or_default();
+            // Saving twice in the same second mustn't replace the first backup
+            let mut backup_path = dir.join(format!("{}.{}~", prefix, stamp));
+            let mut counter = 0;
+            while std::fs::symlink_metadata(&backup_path).is_ok() {
+                counter += 1;
+                backup_path = dir.join(format!("{}.{}.{}~", prefix, stamp, counter));
+            }
+            backup_path
+        }
+    };
+    let error = |e| FileError::new(FileOperation::Backup, &backup_path, e);
+
+    match std::fs::remove_file(&backup_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(error(e)),
+        _ => {}
+    }
+    let linked = method == BackupMethod::Link && std::fs::hard_link(path, &backup_path).is_ok();
+    if !linked {
+        // Also taken when the backup folder is on another file system
+        std::fs::copy(path, &backup_path).map_err(error)?;
+    }
+
+    if let Some(dir) = &settings.directory {
+        prune_backups(dir, path, settings.keep);
+    }
+    Ok(())
+}
+
+/// Backups in the backup folder start with the file's whole path, with `/`
+/// replaced by `!`, so files with the same name don't mix
+fn backup_prefix(path: &Path) -> String {
+    std::path::absolute(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .replace('/', "!")
+}
+
+/// Delete the oldest backups of `path` in `dir`, so only `keep` are left
+fn prune_backups(dir: &Path, path: &Path, keep: usize) {
+    if keep == 0 {
+        return;
+    }
+    let prefix = format!("{}.", backup_prefix(path));
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    // Backups of `config.old` also start with the prefix of `config`, so the
+    // rest has to be just a timestamp
+    let mut backups: Vec<((String, u32), PathBuf)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let (stamp, counter) = backup_stamp(name.strip_prefix(&prefix)?)?;
+            Some(((stamp.to_string(), counter), entry.path()))
+        })
+        .collect();
+    // Oldest first
+    backups.sort();
+
+    let excess = backups.len().saturating_sub(keep);
+    for (_, backup) in &backups[..excess] {
+        if let Err(e) = std::fs::remove_file(backup) {
+            println!("Error removing old backup {}: {}", backup.display(), e);
+        }
+    }
+}
+
+/// The timestamp and counter in what follows the prefix of a backup's name,
+/// like `2024-01-31_23-59-59~`, or `2024-01-31_23-59-59.2~` for the third
+/// backup in that second
+fn backup_stamp(rest: &str) -> Option<(&str, u32)> {
+    let rest = rest.strip_suffix('~')?;
+    let (stamp, counter) = match rest.split_once('.') {
+        Some((stamp, counter)) if counter.bytes().all(|b| b.is_ascii_digit()) => {
+            (stamp, counter.parse().ok()?)
+        }
+        Some(_) => return None,
+        None => (rest, 0),
+    };
+    is_backup_timestamp(stamp).then_some((stamp, counter))
+}
+
+/// Whether `text` is a timestamp in [`BACKUP_TIMESTAMP_FORMAT`], like
+/// `2024-01-31_23-59-59`
+fn is_backup_timestamp(text: &str) -> bool {
+    const PATTERN: &[u8] = b"0000-00-00_00-00-00";
+    text.len() == PATTERN.len()
+        && text
+            .bytes()
+            .zip(PATTERN)
+            .all(|(byte, &pattern)| match pattern {
+                b'0' => byte.is_ascii_digit(),
+                _ => byte == pattern,
+            })
+}
+
+/// Follow `path` through symlinks to the file they point to, which doesn't
+/// need to exist yet
+fn resolve_symlinks(path: &Path) -> std::io::Result<PathBuf> {
+    let mut path = path.to_path_buf();
+    for _ in 0..MAX_SYMLINK_DEPTH {
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let link = std::fs::read_link(&path)?;
+                // Relative links are relative to the link's own directory
+                path = match path.parent() {
+                    Some(parent) => parent.join(link),
+                    None => link,
+                };
+            }
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(path),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(std::io::Error::other("Too many levels of symbolic links"))
+}
+
+/// Overwrite the file at `path`, truncating it first
+fn write_in_place(path: &Path, content: &[u8], progress: &Progress) -> std::io::Result<()> {
+    progress.in_place.store(true, Ordering::Relaxed);
+    let mut file = File::create(path)?;
+    write_chunks(&mut file, content, progress, false)?;
+    file.sync_all()
+}
+
+/// Write `content` to `file` in chunks, reporting progress and, if
+/// `cancellable`, stopping once cancelled
+fn write_chunks(
+    file: &mut File,
+    content: &[u8],
+    progress: &Progress,
+    cancellable: bool,
+) -> std::io::Result<()> {
+    progress.start(content.len() as u64);
+    for chunk in content.chunks(CHUNK_SIZE) {
+        if cancellable {
+            progress.check_cancelled()?;
+        }
+        file.write_all(chunk)?;
+        progress.advance(chunk.len());
+    }
+    Ok(())
+}
+
+enum AtomicWriteError {
+    /// The temporary file couldn't be created
+    TempFile(std::io::Error),
+    /// Writing the temporary file or renaming it over the target failed
+    Write(std::io::Error),
+    /// The previous contents couldn't be backed up, nothing was replaced
+    Backup(FileError),
+}
+
+impl From<std::io::Error> for AtomicWriteError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Write(e)
+    }
+}
+
+/// Write to a temporary file in the same directory as `path`, then rename it
+/// over `path`
+fn write_atomically(
+    path: &Path,
+    content: &[u8],
+    existing: Option<&std::fs::Metadata>,
+    backup: Option<&BackupSettings>,
+    progress: &Progress,
+) -> Result<(), AtomicWriteError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let (temp_path, mut file) = create_temp_file(dir, path).map_err(AtomicWriteError::TempFile)?;
+
+    let result = (|| {
+        write_chunks(&mut file, content, progress, true)?;
+        if let Some(existing) = existing {
+            copy_metadata(path, &temp_path, &file, existing)?;
+        }
+        file.sync_all()?;
+        drop(file);
+        // Last chance to cancel before the file is replaced
+        progress.check_cancelled()?;
+        // The old file is left untouched by the rename, so linking it is a
+        // complete backup
+        if let Some(backup) = backup {
+            make_backup(path, backup, BackupMethod::Link).map_err(AtomicWriteError::Backup)?;
+        }
+        std::fs::rename(&temp_path, path)?;
+        // Make the rename itself durable. The file is already replaced by
+        // now, so failing here would only make a retry save it again.
+        if let Err(e) = File::open(dir).and_then(|dir| dir.sync_all()) {
+            println!("Error syncing {}: {}", dir.display(), e);
+        }
+        Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Create a new, hidden temporary file in `dir` named after `path`
+fn create_temp_file(dir: &Path, path: &Path) -> std::io::Result<(PathBuf, File)> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut suffix = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default()
+        ^ std::process::id();
+
+    loop {
+        let temp_path = dir.join(format!(".{}.{:08x}.tmp", name, suffix));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+        {
+            Ok(file) => return Ok((temp_path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => suffix = suffix.wrapping_add(1),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Give the temporary file the mode, owner and extended attributes of the
+/// file it replaces
+fn copy_metadata(
+    path: &Path,
+    temp_path: &Path,
+    file: &File,
+    existing: &std::fs::Metadata,
+) -> std::io::Result<()> {
+    // Only root can give a file away, so a failed chown keeps our ownership,
+    // just like writing in place would for a new file
+    let _ = std::os::unix::fs::fchown(file, Some(existing.uid()), Some(existing.gid()));
+    // The mode goes after chown, which may clear the setuid and setgid bits
+    file.set_permissions(existing.permissions())?;
+
+    // Some attributes, like SELinux labels, may need privileges to set,
+    // those are skipped rather than failing the save
+    if let Ok(names) = xattr::list(path) {
+        for name in names {
+            if let Ok(Some(value)) = xattr::get(path, &name) {
+                let _ = xattr::set(temp_path, &name, &value);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Empty directory for one test, removed again when dropped
+    pub(crate) struct TempDir(pub(crate) PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("enigmata-test-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn names_in(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn saves_new_file() {
+        let temp = TempDir::new("save-new");
+        let path = temp.0.join("new.txt");
+        save_file(&path, b"hello\n", None).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello\n");
+        // The temporary file is gone after the rename
+        assert_eq!(names_in(&temp.0), vec!["new.txt"]);
+    }
+
+    #[test]
+    fn save_keeps_mode_and_symlink() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = TempDir::new("save-link");
+        let path = temp.0.join("script.sh");
+        std::fs::write(&path, "old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o750)).unwrap();
+        let link = temp.0.join("link.sh");
+        std::os::unix::fs::symlink("script.sh", &link).unwrap();
+
+        save_file(&link, b"new", None).unwrap();
+
+        assert!(std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+    }
+
+    #[test]
+    fn save_keeps_hard_links_together() {
+        let temp = TempDir::new("save-hard-link");
+        let path = temp.0.join("a.txt");
+        let other = temp.0.join("b.txt");
+        std::fs::write(&path, "old").unwrap();
+        std::fs::hard_link(&path, &other).unwrap();
+
+        save_file(&path, b"new", None).unwrap();
+
+        assert_eq!(std::fs::read(&other).unwrap(), b"new");
+    }
+
+    #[test]
+    fn cancelled_save_leaves_file_alone() {
+        let temp = TempDir::new("save-cancel");
+        let path = temp.0.join("file.txt");
+        std::fs::write(&path, "old").unwrap();
+        let progress = Progress::default();
+        progress.cancel();
+
+        let error = save_file_with_progress(&path, b"new", None, &progress).unwrap_err();
+
+        assert!(error.is_cancelled());
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        assert_eq!(names_in(&temp.0), vec!["file.txt"]);
+    }
+
+    #[test]
+    fn save_backs_up_only_when_enabled() {
+        let temp = TempDir::new("save-backup");
+        let path = temp.0.join("notes.txt");
+        std::fs::write(&path, "first").unwrap();
+        let mut settings = BackupSettings {
+            enabled: false,
+            directory: None,
+            keep: 0,
+        };
+
+        save_file(&path, b"second", Some(&settings)).unwrap();
+        assert_eq!(names_in(&temp.0), vec!["notes.txt"]);
+
+        settings.enabled = true;
+        save_file(&path, b"third", Some(&settings)).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"third");
+        assert_eq!(std::fs::read(temp.0.join("notes.txt~")).unwrap(), b"second");
+    }
+
+    #[test]
+    fn failed_backup_leaves_file_alone_until_retried() {
+        let temp = TempDir::new("save-backup-fails");
+        let path = temp.0.join("notes.txt");
+        std::fs::write(&path, "old").unwrap();
+        // A file where the backup folder should be
+        let blocker = temp.0.join("backups");
+        std::fs::write(&blocker, "").unwrap();
+        let settings = BackupSettings {
+            enabled: true,
+            directory: Some(blocker.clone()),
+            keep: 5,
+        };
+
+        let error = save_file(&path, b"new", Some(&settings)).unwrap_err();
+
+        assert_eq!(error.operation, FileOperation::Backup);
+        assert_eq!(error.path, blocker);
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+
+        // Retrying goes to the file that was being saved, not the backup
+        save_file(&path, b"new", None).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(std::fs::read(&blocker).unwrap(), b"");
+    }
+
+    #[test]
+    fn backs_up_next_to_file() {
+        let temp = TempDir::new("backup-next");
+        let path = temp.0.join("notes.txt");
+        std::fs::write(&path, "old").unwrap();
+        let settings = BackupSettings {
+            enabled: true,
+            directory: None,
+            keep: 0,
+        };
+
+        make_backup(&path, &settings, BackupMethod::Link).unwrap();
+
+        assert_eq!(std::fs::read(temp.0.join("notes.txt~")).unwrap(), b"old");
+    }
+
+    #[test]
+    fn backs_up_to_folder_with_timestamp() {
+        let temp = TempDir::new("backup-folder");
+        let path = temp.0.join("notes.txt");
+        std::fs::write(&path, "old").unwrap();
+        let backups = temp.0.join("backups");
+        let settings = BackupSettings {
+            enabled: true,
+            directory: Some(backups.clone()),
+            keep: 5,
+        };
+
+        make_backup(&path, &settings, BackupMethod::Copy).unwrap();
+        // Changing the file mustn't change a copied backup
+        std::fs::write(&path, "new").unwrap();
+
+        let names = names_in(&backups);
+        assert_eq!(names.len(), 1);
+        let stamp = names[0]
+            .strip_prefix(&format!("{}.", backup_prefix(&path)))
+            .and_then(|rest| rest.strip_suffix('~'))
+            .unwrap();
+        assert!(is_backup_timestamp(stamp));
+        assert_eq!(std::fs::read(backups.join(&names[0])).unwrap(), b"old");
+    }
+
+    #[test]
+    fn keeps_backups_made_in_the_same_second() {
+        let temp = TempDir::new("backup-same-second");
+        let path = temp.0.join("notes.txt");
+        let backups = temp.0.join("backups");
+        let settings = BackupSettings {
+            enabled: true,
+            directory: Some(backups.clone()),
+            keep: 5,
+        };
+
+        for content in ["first", "second", "third"] {
+            std::fs::write(&path, content).unwrap();
+            make_backup(&path, &settings, BackupMethod::Copy).unwrap();
+        }
+
+        let mut contents: Vec<String> = names_in(&backups)
+            .iter()
+            .map(|name| std::fs::read_to_string(backups.join(name)).unwrap())
+            .collect();
+        contents.sort();
+        assert_eq!(contents, vec!["first", "second", "third"]);
+    }
+
+    #[test]
+    fn recognizes_backup_stamps() {
+        assert_eq!(
+            backup_stamp("2024-01-31_23-59-59~"),
+            Some(("2024-01-31_23-59-59", 0))
+        );
+        assert_eq!(
+            backup_stamp("2024-01-31_23-59-59.12~"),
+            Some(("2024-01-31_23-59-59", 12))
+        );
+        assert_eq!(backup_stamp("2024-01-31_23-59-59"), None);
+        assert_eq!(backup_stamp("2024-01-31_23-59-59.+1~"), None);
+        assert_eq!(backup_stamp("old.2024-01-31_23-59-59~"), None);
+    }
+
+    #[test]
+    fn recognizes_backup_timestamps() {
+        assert!(is_backup_timestamp("2024-01-31_23-59-59"));
+        assert!(!is_backup_timestamp("old.2024-01-31_23-59-59"));
+        assert!(!is_backup_timestamp("2024-01-31_23-59"));
+        assert!(!is_backup_timestamp("2024-01-31 23-59-59"));
+    }
+
+    #[test]
+    fn prunes_only_backups_of_the_same_file() {
+        let temp = TempDir::new("prune");
+        let backups = temp.0.join("backups");
+        std::fs::create_dir(&backups).unwrap();
+        let path = temp.0.join("config");
+        let other = temp.0.join("config.old");
+
+        let stamps = [
+            "2024-01-01_00-00-00",
+            "2024-01-02_00-00-00",
+            "2024-01-03_00-00-00",
+        ];
+        for file in [&path, &other] {
+            for stamp in stamps {
+                let name = format!("{}.{}~", backup_prefix(file), stamp);
+                std::fs::write(backups.join(name), "backup").unwrap();
+            }
+        }
+
+        // Later backups in the same second, which sort by their counter
+        for counter in [10, 2] {
+            let name = format!("{}.2024-01-02_00-00-00.{}~", backup_prefix(&path), counter);
+            std::fs::write(backups.join(name), "backup").unwrap();
+        }
+
+        prune_backups(&backups, &path, 1);
+
+        let prefix = backup_prefix(&path);
+        let other_prefix = backup_prefix(&other);
+        assert_eq!(
+            names_in(&backups),
+            vec![
+                format!("{}.2024-01-03_00-00-00~", prefix),
+                format!("{}.2024-01-01_00-00-00~", other_prefix),
+                format!("{}.2024-01-02_00-00-00~", other_prefix),
+                format!("{}.2024-01-03_00-00-00~", other_prefix),
+            ]
+        );
+    }
+}
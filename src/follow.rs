@@ -0,0 +1,221 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use encoding_rs::Encoding;
+
+use crate::file_format;
+use crate::file_io::{self, FileError};
+
+/// Most bytes read in one check, so a burst of output doesn't hold up the UI
+const MAX_READ: u64 = 512 * 1024;
+
+/// What happened to a followed file since it was last checked
+#[derive(Debug)]
+pub(crate) enum FollowUpdate {
+    Unchanged,
+    /// Text was added to the end, with line endings turned into `\n`
+    Appended(String),
+    /// The file was truncated or replaced, as when a log is rotated, so it
+    /// has to be loaded again
+    Reload,
+}
+
+/// Follows a file as it grows, like `tail -f`
+pub(crate) struct Follower {
+    /// Bytes of the file that are already in the buffer
+    offset: u64,
+    /// Device and inode of the file, which change when it's replaced
+    identity: Option<(u64, u64)>,
+    /// Keeps characters split across two reads whole
+    decoder: encoding_rs::Decoder,
+    /// The last read ended in `\r`, which may be half of a `\r\n`
+    pending_cr: bool,
+    /// The file had more than one check could read
+    behind: bool,
+}
+
+impl Follower {
+    /// Follow `path` from `offset`, after that many of its bytes were loaded
+    /// in `encoding`
+    pub(crate) fn new(path: &Path, encoding: &'static Encoding, offset: u64) -> Self {
+        Self {
+            offset,
+            identity: std::fs::metadata(path).ok().map(|m| (m.dev(), m.ino())),
+            decoder: encoding.new_decoder_without_bom_handling(),
+            pending_cr: false,
+            behind: false,
+        }
+    }
+
+    /// Bytes of the file that are in the buffer so far
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Whether the last check stopped before the end of the file, so there's
+    /// more to read right away
+    pub(crate) fn is_behind(&self) -> bool {
+        self.behind
+    }
+
+    /// Look for text added to `path` since the last check
+    pub(crate) fn check(&mut self, path: &Path) -> Result<FollowUpdate, FileError> {
+        // A rotated log is briefly gone before the new one is created, which
+        // is another change to check then
+        self.behind = false;
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return Ok(FollowUpdate::Unchanged);
+        };
+        if self.identity != Some((metadata.dev(), metadata.ino())) || metadata.len() < self.offset {
+            return Ok(FollowUpdate::Reload);
+        }
+        if metadata.len() == self.offset {
+            return Ok(FollowUpdate::Unchanged);
+        }
+
+        let appended = file_io::read_appended(path, self.offset, MAX_READ)?;
+        self.offset += appended.len() as u64;
+        self.behind = metadata.len() > self.offset;
+        Ok(FollowUpdate::Appended(self.decode(&appended)))
+    }
+
+    fn decode(&mut self, bytes: &[u8]) -> String {
+        let mut text = String::new();
+        if std::mem::take(&mut self.pending_cr) {
+            text.push('\r');
+        }
+        if let Some(len) = self.decoder.max_utf8_buffer_length(bytes.len()) {
+            text.reserve(len);
+        }
+        let _ = self.decoder.decode_to_string(bytes, &mut text, false);
+
+        if text.ends_with('\r') {
+            text.pop();
+            self.pending_cr = true;
+        }
+        file_format::normalize_line_endings(&text).0.into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::file_io::tests::TempDir;
+
+    fn append(path: &Path, bytes: &[u8]) {
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .unwrap()
+            .write_all(bytes)
+            .unwrap();
+    }
+
+    fn appended(follower: &mut Follower, path: &Path) -> String {
+        match follower.check(path).unwrap() {
+            FollowUpdate::Appended(text) => text,
+            update => panic!("expected appended text, got {:?}", update),
+        }
+    }
+
+    #[test]
+    fn appends_new_text() {
+        let temp = TempDir::new("follow-append");
+        let path = temp.0.join("log");
+        std::fs::write(&path, "one\n").unwrap();
+        let mut follower = Follower::new(&path, encoding_rs::UTF_8, 4);
+
+        assert!(matches!(
+            follower.check(&path).unwrap(),
+            FollowUpdate::Unchanged
+        ));
+        append(&path, b"two\n");
+        assert_eq!(appended(&mut follower, &path), "two\n");
+        assert!(matches!(
+            follower.check(&path).unwrap(),
+            FollowUpdate::Unchanged
+        ));
+    }
+
+    #[test]
+    fn keeps_crlf_split_across_reads() {
+        let temp = TempDir::new("follow-crlf");
+        let path = temp.0.join("log");
+        std::fs::write(&path, "").unwrap();
+        let mut follower = Follower::new(&path, encoding_rs::UTF_8, 0);
+
+        append(&path, b"a\r");
+        assert_eq!(appended(&mut follower, &path), "a");
+        append(&path, b"\nb\r\n");
+        assert_eq!(appended(&mut follower, &path), "\nb\n");
+    }
+
+    #[test]
+    fn keeps_character_split_across_reads() {
+        let temp = TempDir::new("follow-utf8");
+        let path = temp.0.join("log");
+        std::fs::write(&path, "").unwrap();
+        let mut follower = Follower::new(&path, encoding_rs::UTF_8, 0);
+
+        append(&path, &"é".as_bytes()[..1]);
+        assert_eq!(appended(&mut follower, &path), "");
+        append(&path, &"é".as_bytes()[1..]);
+        assert_eq!(appended(&mut follower, &path), "é");
+    }
+
+    #[test]
+    fn decodes_legacy_encoding() {
+        let temp = TempDir::new("follow-legacy");
+        let path = temp.0.join("log");
+        std::fs::write(&path, "").unwrap();
+        let mut follower = Follower::new(&path, encoding_rs::WINDOWS_1252, 0);
+
+        append(&path, b"caf\xe9\r\n");
+        assert_eq!(appended(&mut follower, &path), "café\n");
+    }
+
+    #[test]
+    fn reloads_truncated_or_replaced_file() {
+        let temp = TempDir::new("follow-reload");
+        let path = temp.0.join("log");
+        std::fs::write(&path, "one\n").unwrap();
+
+        let mut follower = Follower::new(&path, encoding_rs::UTF_8, 4);
+        std::fs::write(&path, "").unwrap();
+        assert!(matches!(
+            follower.check(&path).unwrap(),
+            FollowUpdate::Reload
+        ));
+
+        // Rotated, a new file took its place
+        let mut follower = Follower::new(&path, encoding_rs::UTF_8, 0);
+        let rotated = temp.0.join("log.1");
+        std::fs::rename(&path, &rotated).unwrap();
+        assert!(matches!(
+            follower.check(&path).unwrap(),
+            FollowUpdate::Unchanged
+        ));
+        std::fs::write(&path, "new\n").unwrap();
+        assert!(matches!(
+            follower.check(&path).unwrap(),
+            FollowUpdate::Reload
+        ));
+    }
+
+    #[test]
+    fn reads_large_appends_in_pieces() {
+        let temp = TempDir::new("follow-large");
+        let path = temp.0.join("log");
+        std::fs::write(&path, "").unwrap();
+        let mut follower = Follower::new(&path, encoding_rs::UTF_8, 0);
+
+        append(&path, &vec![b'x'; MAX_READ as usize + 10]);
+        assert_eq!(appended(&mut follower, &path).len(), MAX_READ as usize);
+        assert!(follower.is_behind());
+        assert_eq!(appended(&mut follower, &path).len(), 10);
+        assert!(!follower.is_behind());
+        assert_eq!(follower.offset(), MAX_READ + 10);
+    }
+}
@@ -1,6 +1,10 @@
+use std::cell::Cell;
 use std::hash::Hash;
 use std::hash::Hasher;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 
 use gtk4::gio;
 use gtk4::prelude::*;
@@ -10,47 +14,252 @@ use relm4::prelude::*;
 use sourceview5::prelude::ViewExt;
 // use sourceview5::prelude::BufferExt;
 use sourceview5::prelude::*;
+mod cli;
+mod file_format;
+mod file_io;
+mod follow;
+mod project_search;
+mod search_history;
+mod settings;
 mod shortcuts;
 mod ui;
+mod windows;
+
+/// How long typing has to pause before the statistics in the status bar are
+/// updated
+const STATISTICS_DELAY: Duration = Duration::from_millis(150);
+/// How often the progress of loading or saving is shown
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+/// How close to the bottom, in pixels, the view has to be scrolled for
+/// follow mode to keep it at the end
+const FOLLOW_END_SLACK: f64 = 16.0;
+/// How long follow mode waits after a change to the file before reading it,
+/// so a program writing many small pieces doesn't cause a read for each
+const FOLLOW_DELAY: Duration = Duration::from_millis(200);
+/// Mark at the end of the buffer that follow mode scrolls to, so the cursor
+/// and selection stay where they are
+const FOLLOW_END_MARK: &str = "follow-end";
 
 struct MainWindow {
-    text: String,
+    /// The window showing this document
+    window: libhelium::ApplicationWindow,
+
     line: i32,
     column: i32,
+    /// Characters in the buffer, updated once typing pauses
     char_count: i32,
     /// The current file the buffer is associated with
     current_file: Option<std::path::PathBuf>,
 
-    /// Hash of the current file, calculated on load
+    /// Hash of the text last loaded from or saved to the current file, to
+    /// tell if another program changed it
     file_hash: Option<u64>,
 
     /// The actual text input buffer
     buffer: sourceview5::Buffer,
 
-    search_bar: relm4::component::Connector<ui::search::SearchBar>,
+    /// The view displaying `buffer`
+    source_view: sourceview5::View,
+
+    search_bar: relm4::Controller<ui::search::SearchBar>,
+
+    /// Entry for jumping to a line
+    go_to_line: relm4::Controller<ui::go_to_line::GoToLine>,
+
+    /// Panel with the results of searching across files
+    find_in_files: relm4::Controller<ui::find_in_files::FindInFiles>,
+
+    /// Read-only view of `current_file` when it looks binary
+    hex_view: relm4::Controller<ui::hex_view::HexView>,
+
+    /// Cursor position to move to once the buffer is loaded, as 1-based line and column
+    pending_cursor: Option<(i32, i32)>,
 
     /// Indicates if the buffer has unsaved changes, AKA "dirty"
+    ///
+    /// Follows the buffer's modified flag, which undoing back to the last
+    /// load or save clears again, and `needs_save`.
     is_dirty: bool,
+    /// The document needs saving even though the buffer's text is as it was
+    /// loaded or saved, because the format changed or no file holds it yet
+    needs_save: bool,
+
+    /// Where the window is in closing with unsaved changes
+    close_state: CloseState,
+
+    /// Watches `current_file` for changes made by other programs
+    file_monitor: Option<gio::FileMonitor>,
+    /// Modification time and size of `current_file` when it was last loaded
+    /// or saved
+    disk_stamp: Option<FileStamp>,
+    /// Change another program made to `current_file`, shown in a banner
+    disk_change: Option<DiskChange>,
+
+    /// Encoding of `current_file`, used when saving
+    format: file_format::FileFormat,
+    /// Some bytes of `current_file` weren't valid in its encoding, so saving
+    /// it as is would change them
+    lossy_load: bool,
+    /// `current_file` has more than one kind of line ending, saving it
+    /// unifies them
+    mixed_line_endings: bool,
+    /// `current_file` looks binary and is shown in `hex_view` instead of the
+    /// buffer, so it can't be saved
+    binary: bool,
+
+    /// Load or save running in the background, the buffer can't be edited
+    /// until it's done
+    operation: Option<Operation>,
+
+    /// The document is too large for the features that make editing slow,
+    /// see [`settings::LargeFileSettings`]
+    large_file: bool,
+    /// The banner explaining large file mode was closed
+    large_file_notice_dismissed: bool,
+
+    /// Follow mode is on, text added to `current_file` is appended to the
+    /// buffer, which can't be edited meanwhile
+    following: bool,
+    /// Tracks `current_file` in follow mode, once it's been loaded
+    follower: Option<follow::Follower>,
+    /// A check of the followed file is waiting for `FOLLOW_DELAY` to pass
+    follow_check_scheduled: bool,
+    /// The stateful `app.follow` action toggling follow mode
+    follow_action: gio::SimpleAction,
+}
+
+/// A file being loaded or saved on another thread
+struct Operation {
+    kind: file_io::FileOperation,
+    progress: Arc<file_io::Progress>,
+    /// Shows the progress in the status bar while it runs
+    timer: gtk::glib::SourceId,
+}
+
+impl Operation {
+    /// What's happening, for the status bar
+    fn description(&self) -> String {
+        let action = match self.kind {
+            file_io::FileOperation::Load => "Opening",
+            _ => "Saving",
+        };
+        format!("{}… {:.0}%", action, self.progress.fraction() * 100.0)
+    }
+}
+
+/// How another program changed the file being edited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiskChange {
+    Modified,
+    Deleted,
+}
+
+/// Why the current file is read again after it changed on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiskRead {
+    /// See if its text changed, or only its modification time
+    Check,
+    /// Remember what's on disk, so the buffer counts as unsaved against it
+    KeepBuffer,
+    /// Show how the buffer differs from it
+    Diff,
+}
+
+/// Steps of closing a window with unsaved changes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum CloseState {
+    /// Not closing
+    #[default]
+    Open,
+    /// "Save" was picked in the unsaved changes dialog, the window closes once
+    /// the save succeeds and stays open if it fails or is cancelled
+    SavingBeforeClose,
 }
 
 #[derive(Debug)]
 pub enum AppMsg {
-    /// Emits when the text in the editor changes
-    TextChanged(String),
     /// Emits when the cursor position changes
-    UpdateCursorPos(i32, i32, i32),
+    UpdateCursorPos(i32, i32),
+    /// Emits once typing pauses, to update the statistics in the status bar
+    UpdateStatistics,
 
     /// Opens file dialog
     Open,
+    /// Load a file from the command line, in this window if it's still
+    /// empty or in a new one otherwise
+    OpenFile(cli::OpenRequest),
+    /// Load text read from standard input as an untitled document, in this
+    /// window if it's still empty or in a new one otherwise
+    OpenText(
+        file_format::DecodedText,
+        Option<gio::ApplicationCommandLine>,
+    ),
     /// Set the contents of the buffer, used in conjunction with `LoadBuffer`
     SetBufferData(String),
+    /// Mark the buffer as having changes no file holds yet
+    MarkUnsaved,
+    /// The buffer's modified flag changed, from editing, undoing or saving
+    ModifiedChanged,
     /// Save current file to disk
     /// If no file path is set, calls `SaveAs`
     Save,
     /// Save current file to disk with a new name
     /// Calls `SaveBuffer` with the new file path
     SaveAs,
+    /// `SaveAs`, writing the buffer in the given format
+    SaveAsWithFormat(file_format::FileFormat),
+    /// A save was given up on, from the save dialog or after it failed
+    SaveCancelled,
+    /// Save, then close the window once the save succeeded
+    SaveAndClose,
+    /// Something happened to the current file on disk, see if it was changed
+    CheckDiskFile,
+    /// The current file was read again in the background, with its stamp
+    /// from before reading and the hash of its text along with the text
+    DiskFileRead(
+        PathBuf,
+        DiskRead,
+        Option<FileStamp>,
+        Result<(u64, String), file_io::FileError>,
+    ),
+    /// Replace the buffer with the file on disk
+    ReloadFromDisk,
+    /// Load the current file into the buffer even though it looks binary
+    OpenAsText,
+    /// A file finished loading in the background
+    FileLoaded(PathBuf, Result<file_io::LoadedFile, file_io::FileError>),
+    /// The buffer finished saving in the background, with the content that
+    /// was written and the format it was written in
+    FileWritten(
+        PathBuf,
+        Vec<u8>,
+        file_format::FileFormat,
+        Result<(), file_io::FileError>,
+    ),
+    /// Show how far the running load or save is
+    ShowProgress,
+    /// Stop the running load or save
+    CancelOperation,
+    /// Turn the features large file mode turned off back on
+    LeaveLargeFileMode,
+    /// Hide the banner explaining large file mode
+    DismissLargeFileNotice,
+    /// Turn follow mode on or off
+    ToggleFollow,
+    /// Reload the current file and follow it from its end
+    StartFollowing,
+    /// The current file changed on disk, check it soon in follow mode
+    FollowFile,
+    /// Append text added to the current file to the buffer in follow mode
+    CheckFollowedFile,
+    /// Keep the buffer as it is after the file changed on disk
+    KeepBufferChanges,
+    /// Show how the file on disk differs from the buffer
+    ShowDiskDiff,
     // SaveContent(String),
+    /// Close this window, asking first if there are unsaved changes
+    Close,
+    /// Close every window, asking for each one with unsaved changes
     Quit,
     Idk,
     /// Displays about dialog
@@ -58,12 +267,39 @@ pub enum AppMsg {
 
     /// Find/Search
     Find,
+    /// Show or hide the find in files panel
+    ToggleFindInFiles,
+    /// Search across the files in a folder
+    SearchInFiles(search_history::SearchQuery),
+    /// Preview replacing across the files in a folder
+    ReplaceInFiles(search_history::SearchQuery, String),
+    /// Files were changed on disk by replacing across files
+    FilesChanged(Vec<PathBuf>),
+    /// Open a file and move the cursor to a 1-based line and column
+    OpenMatch(PathBuf, i32, i32),
+    /// Opens the go to line entry
+    GoToLine,
+    /// Move the cursor to a line entered in the go to line entry
+    GoTo(ui::go_to_line::LineTarget),
+    /// Give the focus back to the text view
+    FocusView,
 
     // Messages for i/o
     /// Load file to buffer
     LoadBuffer(PathBuf),
-    /// Save buffer to file
-    SaveBuffer(PathBuf, String),
+    /// Save buffer to file in the given format
+    SaveBuffer(PathBuf, String, file_format::FileFormat),
+    /// Write the buffer, already encoded in the given format, to file
+    WriteFile(PathBuf, Vec<u8>, file_format::FileFormat),
+    /// Load the current file again with the given encoding, asking first if
+    /// there are unsaved changes
+    ReopenWithEncoding(file_format::FileFormat),
+    /// Load the current file again with the given encoding
+    ReloadWithEncoding(&'static encoding_rs::Encoding),
+    /// Save the buffer with a different encoding from now on
+    SaveWithEncoding(file_format::FileFormat),
+    /// Save the buffer with different line endings from now on
+    SetLineEnding(file_format::LineEnding),
 
     SetStyleScheme(sourceview5::StyleScheme),
     SelectStyleScheme,
@@ -75,11 +311,24 @@ pub enum AppMsg {
 impl MainWindow {
     fn default_file_name(&self) -> String {
         const UNTITLED: &str = "Untitled.txt";
-        self.current_file
-            .as_ref()
-            .map(|f| f.file_name().and_then(|f| f.to_str()).unwrap_or(UNTITLED))
-            .unwrap_or_else(|| UNTITLED)
-            .to_string()
+        match &self.current_file {
+            Some(file) => file
+                .file_name()
+                .and_then(|f| f.to_str())
+                .unwrap_or(UNTITLED)
+                .to_string(),
+            // Untitled documents get an extension matching their highlighting,
+            // e.g. `Untitled.diff` for piped `git diff` output
+            None => self
+                .buffer
+                .language()
+                .and_then(|lang| lang.globs().into_iter().next())
+                .and_then(|glob| {
+                    glob.strip_prefix("*.")
+                        .map(|ext| format!("Untitled.{}", ext))
+                })
+                .unwrap_or_else(|| UNTITLED.to_string()),
+        }
     }
 
     fn guess_language_from_file(&self) -> Option<sourceview5::Language> {
@@ -92,22 +341,681 @@ impl MainWindow {
         l
     }
 
+    /// Folder to search in across files, next to the current file by default
+    fn default_search_folder(&self) -> Option<PathBuf> {
+        self.current_file
+            .as_ref()
+            .and_then(|f| f.parent())
+            .map(PathBuf::from)
+            .or_else(|| std::env::current_dir().ok())
+    }
+
+    /// Move the cursor to a 1-based line and column, and scroll it into view
+    fn go_to(&self, line: i32, column: i32) {
+        let buffer = &self.buffer;
+        let iter = buffer
+            .iter_at_line_offset(line - 1, column - 1)
+            .or_else(|| buffer.iter_at_line(line - 1))
+            .unwrap_or_else(|| buffer.end_iter());
+        buffer.place_cursor(&iter);
+        self.source_view
+            .scroll_to_mark(&buffer.get_insert(), 0.0, true, 0.0, 0.3);
+        self.source_view.grab_focus();
+    }
+
+    /// Tell the user a file couldn't be opened
+    fn show_load_error(&self, error: &file_io::FileError) {
+        let alert = gtk::AlertDialog::builder()
+            .message("Could not open file")
+            .detail(format!("{}\n\n{}", error.reason(), error.path.display()))
+            .buttons(vec!["Close"])
+            .cancel_button(0)
+            .default_button(0)
+            .modal(true)
+            .build();
+        alert.show(Some(&self.window));
+    }
+
+    /// Tell the user saving to `file_path` failed, offering to try again or
+    /// save elsewhere
+    ///
+    /// The error's own path may be the backup instead, it's only shown. The
+    /// buffer stays marked as unsaved until a save succeeds.
+    fn show_save_error(
+        &self,
+        error: &file_io::FileError,
+        file_path: PathBuf,
+        content: Vec<u8>,
+        format: file_format::FileFormat,
+        sender: ComponentSender<Self>,
+    ) {
+        let alert = gtk::AlertDialog::builder()
+            .message(match error.operation {
+                file_io::FileOperation::Backup => "Could not back up file",
+                _ => "Could not save file",
+            })
+            .detail(format!(
+                "{}\n\n{}\n\nYour changes haven't been saved.",
+                error.reason(),
+                error.path.display()
+            ))
+            .buttons(vec!["Cancel", "Save As…", "Retry"])
+            .cancel_button(0)
+            .default_button(2)
+            .modal(true)
+            .build();
+        alert.choose(
+            Some(&self.window),
+            None::<&gio::Cancellable>,
+            move |response| match response {
+                Ok(1) => sender.input(AppMsg::SaveAsWithFormat(format)),
+                Ok(2) => sender.input(AppMsg::WriteFile(file_path, content, format)),
+                _ => sender.input(AppMsg::SaveCancelled),
+            },
+        );
+    }
+
+    /// Ask before saving text that can't be written back exactly in `format`
+    fn confirm_lossy_save(
+        &self,
+        file_path: PathBuf,
+        content: Vec<u8>,
+        format: file_format::FileFormat,
+        unmappable: bool,
+        sender: ComponentSender<Self>,
+    ) {
+        let encoding = format.name();
+        let mut reasons = Vec::new();
+        if unmappable {
+            reasons.push(format!(
+                "Some characters can't be represented in {}, they will be saved as character references like \"&#8364;\" instead.",
+                encoding
+            ));
+        }
+        if self.lossy_load {
+            reasons.push(format!(
+                "The file had bytes that aren't valid {}, they were replaced when it was opened. Saving makes that permanent.",
+                encoding
+            ));
+        }
+        if self.mixed_line_endings {
+            reasons.push(format!(
+                "The file mixes different line endings, they will all be saved as {}.",
+                format.line_ending.name()
+            ));
+        }
+        let detail = reasons.join("\n\n");
+        let alert = gtk::AlertDialog::builder()
+            .message("Text can't be saved exactly")
+            .detail(detail)
+            .buttons(vec!["Cancel", "Save Anyway"])
+            .cancel_button(0)
+            .default_button(0)
+            .modal(true)
+            .build();
+        alert.choose(
+            Some(&self.window),
+            None::<&gio::Cancellable>,
+            move |response| match response {
+                Ok(1) => sender.input(AppMsg::WriteFile(file_path, content, format)),
+                _ => sender.input(AppMsg::SaveCancelled),
+            },
+        );
+    }
+
+    /// Load a file into the buffer, in `encoding` or the detected one if `None`
+    ///
+    /// Files that look binary are shown read-only in the hex view instead,
+    /// unless an encoding is given or `as_text` is set.
+    ///
+    /// The file is read on another thread, `FileLoaded` carries on from there.
+    fn load_buffer(
+        &mut self,
+        file_path: PathBuf,
+        encoding: Option<&'static encoding_rs::Encoding>,
+        as_text: bool,
+        sender: &ComponentSender<Self>,
+    ) {
+        if self.operation.is_some() {
+            println!(
+                "Still loading or saving, not opening {}",
+                file_path.display()
+            );
+            self.pending_cursor = None;
+            return;
+        }
+        let progress = self.start_operation(file_io::FileOperation::Load, sender);
+        let sender = sender.clone();
+        gtk::glib::spawn_future_local(async move {
+            let path = file_path.clone();
+            let result = gio::spawn_blocking(move || {
+                file_io::load_document(&path, encoding, as_text, &progress)
+            })
+            .await
+            .unwrap_or_else(|_| {
+                Err(file_io::FileError::new(
+                    file_io::FileOperation::Load,
+                    &file_path,
+                    std::io::Error::other("Reading the file crashed"),
+                ))
+            });
+            sender.input(AppMsg::FileLoaded(file_path, result));
+        });
+    }
+
+    /// Show a file read by `load_buffer`
+    fn finish_load(
+        &mut self,
+        file_path: PathBuf,
+        result: Result<file_io::LoadedFile, file_io::FileError>,
+        sender: &ComponentSender<Self>,
+    ) {
+        self.finish_operation();
+        let decoded = match result {
+            Ok(file_io::LoadedFile::Text(decoded)) => decoded,
+            Ok(file_io::LoadedFile::Binary(bytes)) => {
+                println!(
+                    "File looks binary, showing it read-only: {}",
+                    file_path.display()
+                );
+                self.binary = true;
+                self.large_file = false;
+                self.format = file_format::FileFormat::default();
+                self.lossy_load = false;
+                self.mixed_line_endings = false;
+                self.pending_cursor = None;
+                self.set_following(false);
+                self.hex_view.emit(ui::hex_view::HexViewMsg::Show(bytes));
+                self.set_buffer_text("");
+                self.bind_to_file(file_path, sender);
+                sender.input(AppMsg::SetLanguage(None));
+                return;
+            }
+            Err(e) => {
+                if e.is_cancelled() {
+                    println!("Cancelled opening {}", file_path.display());
+                } else {
+                    println!("Error opening file: {}", e);
+                    self.show_load_error(&e);
+                }
+                self.pending_cursor = None;
+                // Follow mode was waiting for this load to start
+                if self.follower.is_none() {
+                    self.set_following(false);
+                }
+                return;
+            }
+        };
+
+        self.binary = false;
+        if decoded.lossy {
+            println!(
+                "File isn't valid {}, invalid bytes were replaced",
+                decoded.format.name()
+            );
+        }
+        self.format = decoded.format;
+        self.lossy_load = decoded.lossy;
+        self.mixed_line_endings = decoded.mixed_line_endings;
+        self.set_large_file(&decoded.text);
+        self.set_buffer_text(&decoded.text);
+        println!("File opened successfully: {}", file_path.display());
+        if self.following {
+            self.follower = Some(follow::Follower::new(
+                &file_path,
+                self.format.encoding,
+                decoded.byte_len as u64,
+            ));
+            self.scroll_to_end();
+        }
+        self.bind_to_file(file_path, sender);
+        // Set text highlighting
+        let lang = self.guess_language_from_file();
+        sender.input(AppMsg::SetLanguage(lang));
+    }
+
+    /// Turn follow mode on or off, the file has to be loaded again for it
+    /// to actually start following
+    fn set_following(&mut self, following: bool) {
+        self.following = following;
+        self.follow_action.set_state(&following.to_variant());
+        self.update_editable();
+        if !following && self.follower.take().is_some() {
+            // The buffer has everything that was appended, which is what's
+            // on disk now
+            self.file_hash = Some(self.hash_buffer_data());
+            self.disk_stamp = self.current_file.as_deref().and_then(file_stamp);
+        }
+    }
+
+    /// Append text added to the current file in follow mode, or reload it if
+    /// it was truncated or replaced
+    fn follow_file(&mut self, sender: &ComponentSender<Self>) {
+        let (Some(file_path), Some(follower)) = (self.current_file.clone(), &mut self.follower)
+        else {
+            return;
+        };
+        match follower.check(&file_path) {
+            Ok(follow::FollowUpdate::Unchanged) => {}
+            Ok(follow::FollowUpdate::Appended(text)) => {
+                let offset = follower.offset();
+                let behind = follower.is_behind();
+                let at_end = self.source_view.vadjustment().is_none_or(|adjustment| {
+                    adjustment.value() + adjustment.page_size()
+                        >= adjustment.upper() - FOLLOW_END_SLACK
+                });
+                let first_line = self.buffer.line_count() - 1;
+                self.buffer.begin_irreversible_action();
+                self.buffer.insert(&mut self.buffer.end_iter(), &text);
+                self.buffer.end_irreversible_action();
+                // Still the same as the file on disk
+                self.buffer.set_modified(false);
+                if !self.large_file && self.grew_large(offset, first_line) {
+                    println!("Followed file grew large, turning off expensive features");
+                    self.large_file = true;
+                    self.large_file_notice_dismissed = false;
+                    sender.input(AppMsg::SetLanguage(None));
+                }
+                // Scrolling up to read something stops following the end
+                if at_end {
+                    self.scroll_to_end();
+                }
+                // Read the rest after the UI had a turn
+                if behind {
+                    self.schedule_follow_check(sender);
+                }
+            }
+            // Keep the current follower to try again on the next change if a
+            // save is still running
+            Ok(follow::FollowUpdate::Reload) if self.operation.is_none() => {
+                println!(
+                    "File was truncated or replaced, reloading: {}",
+                    file_path.display()
+                );
+                self.follower = None;
+                self.pending_cursor = None;
+                self.load_buffer(file_path, Some(self.format.encoding), false, sender);
+            }
+            Ok(follow::FollowUpdate::Reload) => {}
+            Err(e) => println!("Error following file: {}", e),
+        }
+    }
+
+    /// Check the followed file once `FOLLOW_DELAY` passed, unless a check
+    /// is already coming
+    fn schedule_follow_check(&mut self, sender: &ComponentSender<Self>) {
+        if self.follow_check_scheduled {
+            return;
+        }
+        self.follow_check_scheduled = true;
+        let sender = sender.clone();
+        gtk::glib::timeout_add_local_once(FOLLOW_DELAY, move || {
+            // The window may be gone by now
+            let _ = sender.input_sender().send(AppMsg::CheckFollowedFile);
+        });
+    }
+
+    /// Whether the document became large enough for large file mode, now
+    /// that the file has `size` bytes and lines from `first_line` on were
+    /// appended to
+    fn grew_large(&self, size: u64, first_line: i32) -> bool {
+        let settings = settings::Settings::load().large_file;
+        settings.is_large_size(size)
+            || (first_line..self.buffer.line_count()).any(|line| {
+                self.buffer
+                    .iter_at_line(line)
+                    .is_some_and(|iter| settings.is_long_line(iter.bytes_in_line() as usize))
+            })
+    }
+
+    fn scroll_to_end(&self) {
+        let end = self.buffer.end_iter();
+        let mark = match self.buffer.mark(FOLLOW_END_MARK) {
+            Some(mark) => {
+                self.buffer.move_mark(&mark, &end);
+                mark
+            }
+            None => self.buffer.create_mark(Some(FOLLOW_END_MARK), &end, false),
+        };
+        self.source_view.scroll_to_mark(&mark, 0.0, false, 0.0, 1.0);
+    }
+
+    /// What the status bar shows below the file name
+    fn status_description(&self) -> String {
+        if let Some(operation) = &self.operation {
+            return operation.description();
+        }
+        let mut description = format!("Line {}, Column {}", self.line, self.column);
+        if !self.large_file {
+            description.push_str(&format!(" | Characters: {}", self.char_count));
+        }
+        if self.following {
+            description.push_str(" | Following");
+        }
+        description
+    }
+
+    /// Turn large file mode on or off for `text`, which is about to be loaded
+    fn set_large_file(&mut self, text: &str) {
+        self.large_file = settings::Settings::load().large_file.applies_to(text);
+        self.large_file_notice_dismissed = false;
+        if self.large_file {
+            println!("Large file, turning off expensive features");
+        }
+    }
+
+    /// Track a load or save that's about to run in the background, returning
+    /// the progress it should report to
+    fn start_operation(
+        &mut self,
+        kind: file_io::FileOperation,
+        sender: &ComponentSender<Self>,
+    ) -> Arc<file_io::Progress> {
+        let progress = Arc::new(file_io::Progress::default());
+        let sender = sender.clone();
+        let timer = gtk::glib::timeout_add_local(PROGRESS_INTERVAL, move || {
+            // Stop once the window is gone
+            match sender.input_sender().send(AppMsg::ShowProgress) {
+                Ok(()) => gtk::glib::ControlFlow::Continue,
+                Err(_) => gtk::glib::ControlFlow::Break,
+            }
+        });
+        self.operation = Some(Operation {
+            kind,
+            progress: progress.clone(),
+            timer,
+        });
+        self.update_editable();
+        progress
+    }
+
+    fn finish_operation(&mut self) {
+        if let Some(operation) = self.operation.take() {
+            operation.timer.remove();
+        }
+        self.update_editable();
+    }
+
+    /// Whether a file or piped text can be opened in this window rather than
+    /// a new one, which needs it to be empty and not busy loading a file
+    fn is_reusable(&self) -> bool {
+        self.current_file.is_none() && !self.is_dirty && self.operation.is_none()
+    }
+
+    /// Whether the buffer can be edited, which it can't while it's being
+    /// loaded or saved or a file is followed
+    fn is_editable(&self) -> bool {
+        self.operation.is_none() && !self.following
+    }
+
+    /// Let the search bar know whether it may replace text. A replace
+    /// during a save would be marked as saved once the save is done.
+    fn update_editable(&self) {
+        self.search_bar
+            .emit(ui::search::SearchBarMsg::SetReadOnly(!self.is_editable()));
+    }
+
+    /// Give up on closing after a save failed or was cancelled, the window
+    /// stays open and so does the application
+    fn abort_close(&mut self) {
+        if self.close_state == CloseState::SavingBeforeClose {
+            self.close_state = CloseState::Open;
+            windows::cancel_quit();
+        }
+    }
+
+    /// Replace all text in the buffer, in a step that can't be undone
+    fn set_buffer_text(&mut self, text: &str) {
+        self.buffer.begin_irreversible_action();
+        self.buffer.set_text(text);
+        self.buffer.end_irreversible_action();
+        if let Some((line, column)) = self.pending_cursor.take() {
+            self.go_to(line, column);
+        }
+    }
+
+    /// Bind the document to `file_path` after the buffer was loaded from or
+    /// saved to it, so it's clean from here on
+    ///
+    /// Only called once a load or save succeeded, so a failed one leaves the
+    /// document pointing at the file it had before.
+    fn bind_to_file(&mut self, file_path: PathBuf, sender: &ComponentSender<Self>) {
+        self.current_file = Some(file_path);
+        self.file_hash = Some(self.hash_buffer_data());
+        self.needs_save = false;
+        self.buffer.set_modified(false);
+        self.update_dirty();
+        self.watch_current_file(sender);
+    }
+
+    fn update_dirty(&mut self) {
+        self.is_dirty = self.buffer.is_modified() || self.needs_save;
+    }
+
     /// Hash the data in the current buffer
     ///
-    /// Used to determine if the buffer is dirty and requires saving
+    /// Used to tell if the file on disk still holds the buffer's text
     fn hash_buffer_data(&self) -> u64 {
-        let mut hasher = std::hash::DefaultHasher::new();
         let content = self
             .buffer
             .text(&self.buffer.start_iter(), &self.buffer.end_iter(), true);
-        content.hash(&mut hasher);
-        hasher.finish()
+        hash_text(&content)
+    }
+
+    /// Watch the current file for changes made by other programs, from its
+    /// state on disk right now
+    fn watch_current_file(&mut self, sender: &ComponentSender<Self>) {
+        if let Some(monitor) = self.file_monitor.take() {
+            monitor.cancel();
+        }
+        self.disk_change = None;
+        let Some(file_path) = &self.current_file else {
+            return;
+        };
+        self.disk_stamp = file_stamp(file_path);
+
+        match gio::File::for_path(file_path).monitor_file(
+            gio::FileMonitorFlags::WATCH_MOVES,
+            None::<&gio::Cancellable>,
+        ) {
+            Ok(monitor) => {
+                let sender = sender.clone();
+                monitor.connect_changed(move |_, _, _, event| {
+                    // A write in progress ends with `ChangesDoneHint`, only
+                    // follow mode wants to see it while it's going on
+                    if event == gio::FileMonitorEvent::Changed {
+                        sender.input(AppMsg::FollowFile);
+                    } else {
+                        sender.input(AppMsg::CheckDiskFile);
+                    }
+                });
+                self.file_monitor = Some(monitor);
+            }
+            Err(e) => println!("Error watching file: {}", e),
+        }
+    }
+
+    /// Read the current file again on another thread, `DiskFileRead`
+    /// carries on from there
+    fn read_disk_file(&self, purpose: DiskRead, sender: &ComponentSender<Self>) {
+        let Some(file_path) = self.current_file.clone() else {
+            return;
+        };
+        let encoding = self.format.encoding;
+        let sender = sender.clone();
+        gtk::glib::spawn_future_local(async move {
+            let path = file_path.clone();
+            let (stamp, result) = gio::spawn_blocking(move || {
+                // Taken first, so a change while reading isn't missed
+                let stamp = file_stamp(&path);
+                let result = file_io::load_file(&path, Some(encoding))
+                    .map(|decoded| (hash_text(&decoded.text), decoded.text));
+                (stamp, result)
+            })
+            .await
+            .unwrap_or_else(|_| {
+                let error = file_io::FileError::new(
+                    file_io::FileOperation::Load,
+                    &file_path,
+                    std::io::Error::other("Reading the file crashed"),
+                );
+                (None, Err(error))
+            });
+            sender.input(AppMsg::DiskFileRead(file_path, purpose, stamp, result));
+        });
+    }
+
+    /// The current file has different text than when it was last loaded or
+    /// saved, reload it unless that would lose unsaved changes
+    fn changed_on_disk(&mut self, sender: &ComponentSender<Self>) {
+        if self.is_dirty {
+            self.disk_change = Some(DiskChange::Modified);
+        } else {
+            sender.input(AppMsg::ReloadFromDisk);
+        }
     }
+
+    /// Show how the buffer differs from `disk_content`, the current file's
+    /// text on disk
+    fn show_disk_diff(&self, disk_content: &str) {
+        let buffer_content =
+            self.buffer
+                .text(&self.buffer.start_iter(), &self.buffer.end_iter(), false);
+        let name = self.default_file_name();
+        let diff = ui::diff_view::unified_diff(
+            disk_content,
+            &buffer_content,
+            &format!("{} (on disk)", name),
+            &format!("{} (unsaved)", name),
+        );
+        ui::diff_view::show(
+            &self.window,
+            &format!("Changes to {}", name),
+            &diff,
+            self.buffer.style_scheme().as_ref(),
+        );
+    }
+
+    /// Save the buffer to the current file in `format`, or ask where to if
+    /// it has none
+    fn save(&mut self, format: file_format::FileFormat, sender: ComponentSender<Self>) {
+        // Saving the empty buffer would wipe out a binary file, and
+        // replacing a followed file would cut it off from the program
+        // still writing to it
+        if self.binary || self.following {
+            self.abort_close();
+            return;
+        }
+        if let Some(file_path) = self.current_file.clone() {
+            let content = self
+                .buffer
+                .text(&self.buffer.start_iter(), &self.buffer.end_iter(), false)
+                .to_string();
+            // The monitor may not have caught up with a change made
+            // right before saving
+            let stamp = file_stamp(&file_path);
+            if stamp.is_some() && stamp != self.disk_stamp {
+                self.confirm_overwrite(file_path, content, format, sender);
+            } else {
+                sender.input(AppMsg::SaveBuffer(file_path, content, format));
+            }
+        } else {
+            self.save_as(format, sender);
+        }
+    }
+
+    /// Ask where to save the buffer, then save it there in `format`
+    fn save_as(&mut self, format: file_format::FileFormat, sender: ComponentSender<Self>) {
+        if self.binary {
+            self.abort_close();
+            return;
+        }
+        // The saved copy becomes the current file, which isn't the
+        // one being written to
+        if self.following {
+            self.set_following(false);
+        }
+        let file_filter = gtk::FileFilter::new();
+        file_filter.add_mime_type("text/*");
+        file_filter.set_name(Some("Text files"));
+        file_filter.add_pattern("*.txt");
+
+        let file_chooser = gtk::FileDialog::builder()
+            // .filters(&[&file_filter])
+            // .filter(&file_filter)
+            // .action(gtk::FileChooserAction::Save)
+            // .name("Save File")
+            // .modal(true)
+            .title("Save as...")
+            .initial_name(&self.default_file_name())
+            .build();
+
+        // let sender = sender.clone();
+        let model_buffer = self.buffer.clone();
+        file_chooser.save(
+            None::<&gtk::Window>,
+            None::<&gio::Cancellable>,
+            move |res| match res.ok().and_then(|file| file.path()) {
+                Some(file_path) => {
+                    let content = model_buffer
+                        .text(&model_buffer.start_iter(), &model_buffer.end_iter(), false)
+                        .to_string();
+                    sender.input(AppMsg::SaveBuffer(file_path, content, format));
+                }
+                None => sender.input(AppMsg::SaveCancelled),
+            },
+        );
+    }
+
+    /// Ask before saving over changes another program made to the file
+    fn confirm_overwrite(
+        &self,
+        file_path: PathBuf,
+        content: String,
+        format: file_format::FileFormat,
+        sender: ComponentSender<Self>,
+    ) {
+        let alert = gtk::AlertDialog::builder()
+            .message("File changed on disk")
+            .detail(format!(
+                "{} was changed by another program since it was opened. Saving will overwrite those changes.",
+                file_path.display()
+            ))
+            .buttons(vec!["Cancel", "Overwrite"])
+            .cancel_button(0)
+            .default_button(0)
+            .modal(true)
+            .build();
+        alert.choose(
+            Some(&self.window),
+            None::<&gio::Cancellable>,
+            move |response| match response {
+                Ok(1) => sender.input(AppMsg::SaveBuffer(file_path, content, format)),
+                _ => sender.input(AppMsg::SaveCancelled),
+            },
+        );
+    }
+}
+
+/// Hash of a document's text, to tell if it changed
+fn hash_text(text: &str) -> u64 {
+    let mut hasher = std::hash::DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Modification time and size of a file, which change along with its content
+type FileStamp = (std::time::SystemTime, u64);
+
+fn file_stamp(file_path: &std::path::Path) -> Option<FileStamp> {
+    let metadata = std::fs::metadata(file_path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
 }
 
 #[relm4::component]
 impl SimpleComponent for MainWindow {
-    type Init = String;
+    /// File to load once the window is open
+    type Init = Option<cli::OpenRequest>;
     type Input = AppMsg;
     type Output = ();
 
@@ -121,7 +1029,7 @@ impl SimpleComponent for MainWindow {
             set_decorated: true,
 
             connect_close_request[sender] => move |_| {
-                sender.input(AppMsg::Quit);
+                sender.input(AppMsg::Close);
                 gtk::glib::Propagation::Stop
             },
 
@@ -143,11 +1051,94 @@ impl SimpleComponent for MainWindow {
             #[wrap(Some)]
             set_child = &gtk::Box {
                 set_orientation: gtk::Orientation::Vertical,
+
+                #[name = "disk_change_banner"]
+                gtk::Revealer {
+                    set_transition_type: gtk::RevealerTransitionType::SlideDown,
+                    #[watch]
+                    set_reveal_child: model.disk_change.is_some(),
+
+                    gtk::Box {
+                        set_orientation: gtk::Orientation::Horizontal,
+                        set_spacing: 8,
+                        set_margin_all: 8,
+
+                        gtk::Label {
+                            set_hexpand: true,
+                            set_xalign: 0.0,
+                            set_wrap: true,
+                            #[watch]
+                            set_label: match model.disk_change {
+                                Some(DiskChange::Deleted) => "The file was deleted or moved by another program.",
+                                _ => "The file was changed by another program, and you have unsaved changes.",
+                            },
+                        },
+                        gtk::Button {
+                            set_label: "Show Diff",
+                            #[watch]
+                            set_visible: model.disk_change == Some(DiskChange::Modified),
+                            connect_clicked[sender] => move |_| {
+                                sender.input(AppMsg::ShowDiskDiff);
+                            },
+                        },
+                        gtk::Button {
+                            set_label: "Keep Mine",
+                            connect_clicked[sender] => move |_| {
+                                sender.input(AppMsg::KeepBufferChanges);
+                            },
+                        },
+                        gtk::Button {
+                            set_label: "Reload",
+                            add_css_class: "suggested-action",
+                            #[watch]
+                            set_visible: model.disk_change == Some(DiskChange::Modified),
+                            connect_clicked[sender] => move |_| {
+                                sender.input(AppMsg::ReloadFromDisk);
+                            },
+                        },
+                    },
+                },
+
+                #[name = "large_file_banner"]
+                gtk::Revealer {
+                    set_transition_type: gtk::RevealerTransitionType::SlideDown,
+                    #[watch]
+                    set_reveal_child: model.large_file && !model.large_file_notice_dismissed,
+
+                    gtk::Box {
+                        set_orientation: gtk::Orientation::Horizontal,
+                        set_spacing: 8,
+                        set_margin_all: 8,
+
+                        gtk::Label {
+                            set_hexpand: true,
+                            set_xalign: 0.0,
+                            set_wrap: true,
+                            set_label: "This file is large, so syntax highlighting, word wrap, the grid background, current line highlighting and character counts are turned off to keep editing fast.",
+                        },
+                        gtk::Button {
+                            set_label: "Turn Back On",
+                            connect_clicked[sender] => move |_| {
+                                sender.input(AppMsg::LeaveLargeFileMode);
+                            },
+                        },
+                        gtk::Button {
+                            set_icon_name: "window-close-symbolic",
+                            set_tooltip_text: Some("Dismiss"),
+                            add_css_class: "flat",
+                            connect_clicked[sender] => move |_| {
+                                sender.input(AppMsg::DismissLargeFileNotice);
+                            },
+                        },
+                    },
+                },
+
                 #[name = "overlay"]
                 gtk::Overlay {
                     set_hexpand: true,
                     set_vexpand: true,
                     add_overlay: search_bar,
+                    add_overlay: go_to_line,
 
                     #[wrap(Some)]
                     #[name = "main_view"]
@@ -158,15 +1149,30 @@ impl SimpleComponent for MainWindow {
                             set_vexpand: true,
                             set_hexpand: true,
                             set_policy: (gtk::PolicyType::Automatic, gtk::PolicyType::Automatic),
-                            #[name = "source_view"]
-                            sourceview5::View {
+                            #[watch]
+                            set_visible: !model.binary,
+                            #[local_ref]
+                            source_view -> sourceview5::View {
                                 set_expand: true,
                                 set_input_purpose: gtk::InputPurpose::FreeForm,
-                                set_wrap_mode: gtk::WrapMode::WordChar,
+                                #[watch]
+                                set_wrap_mode: if model.large_file {
+                                    gtk::WrapMode::None
+                                } else {
+                                    gtk::WrapMode::WordChar
+                                },
                                 set_show_line_numbers: true,
-                                set_highlight_current_line: true,
+                                #[watch]
+                                set_highlight_current_line: !model.large_file,
                                 set_monospace: true,
-                                set_background_pattern: sourceview5::BackgroundPatternType::Grid,
+                                #[watch]
+                                set_background_pattern: if model.large_file {
+                                    sourceview5::BackgroundPatternType::None
+                                } else {
+                                    sourceview5::BackgroundPatternType::Grid
+                                },
+                                #[watch]
+                                set_editable: model.is_editable(),
                                 // set_extra_menu: Some(&{
                                 //     let menu: gtk4::gio::MenuModel = build_menu().into();
                                 //     menu
@@ -176,9 +1182,24 @@ impl SimpleComponent for MainWindow {
                                 set_accessible_role: gtk::AccessibleRole::TextBox,
                             },
                         },
+
+                        #[local_ref]
+                        hex_view -> gtk::Box {
+                            #[watch]
+                            set_visible: model.binary,
+                        },
                     }, // gtk::Overlay
 
                 }, // gtk::Box 
+                append: find_in_files,
+
+                gtk::ProgressBar {
+                    #[watch]
+                    set_visible: model.operation.is_some(),
+                    #[watch]
+                    set_fraction: model.operation.as_ref().map_or(0.0, |operation| operation.progress.fraction()),
+                },
+
                 #[name = "status_bar"]
                 libhelium::BottomBar {
                     set_css_classes: &["compact"],
@@ -194,7 +1215,7 @@ impl SimpleComponent for MainWindow {
                         if model.is_dirty { "*" } else { "" }
                     ),
                     #[watch]
-                    set_description: &format!("Line {}, Column {} | Characters: {}", model.line, model.column, model.char_count),
+                    set_description: &model.status_description(),
                     set_widget_name: "status_bar",
                     #[name = "open_button_shortcut"]
                     prepend_button[libhelium::BottomBarPosition::Left] = &libhelium::Button {
@@ -212,6 +1233,62 @@ impl SimpleComponent for MainWindow {
                         },
                     },
                     
+                    #[name = "cancel_operation_button"]
+                    append_button[libhelium::BottomBarPosition::Right] = &libhelium::Button {
+                        set_css_classes: &["circular"],
+                        set_tooltip_text: Some("Cancel"),
+                        set_margin_horizontal: 8,
+                        set_icon_name: "process-stop-symbolic",
+                        set_is_iconic: true,
+                        // A save in place can't be stopped halfway
+                        #[watch]
+                        set_visible: model.operation.as_ref().is_some_and(|operation| operation.progress.is_cancellable()),
+                        connect_clicked[sender] => move |_| {
+                            sender.input(AppMsg::CancelOperation);
+                        },
+                    },
+
+                    #[name = "encoding_button"]
+                    append_button[libhelium::BottomBarPosition::Right] = &libhelium::Button {
+                        set_tooltip_text: Some("Encoding"),
+                        set_margin_horizontal: 8,
+                        #[watch]
+                        set_sensitive: !model.binary,
+                        #[watch]
+                        set_label: &model.format.name(),
+                    },
+
+                    #[name = "line_ending_button"]
+                    append_button[libhelium::BottomBarPosition::Right] = &libhelium::Button {
+                        set_margin_horizontal: 8,
+                        #[watch]
+                        set_sensitive: !model.binary,
+                        #[watch]
+                        set_label: &if model.mixed_line_endings {
+                            format!("Mixed ({})", model.format.line_ending.name())
+                        } else {
+                            model.format.line_ending.name().to_string()
+                        },
+                        #[watch]
+                        set_tooltip_text: Some(if model.mixed_line_endings {
+                            "The file mixes line endings, saving unifies them"
+                        } else {
+                            "Line endings"
+                        }),
+                    },
+
+                    #[name = "go_to_line_button"]
+                    append_button[libhelium::BottomBarPosition::Right] = &libhelium::Button {
+                        set_css_classes: &["circular"],
+                        set_tooltip_text: Some("Go to line..."),
+                        set_margin_horizontal: 8,
+                        set_icon_name: "go-jump-symbolic",
+                        set_is_iconic: true,
+                        connect_clicked[sender] => move |_| {
+                            sender.input(AppMsg::GoToLine);
+                        },
+                    },
+
                     #[name = "search_button_shortcut"]
                     append_button[libhelium::BottomBarPosition::Right] = &libhelium::Button {
                         // set_is_pill: true,
@@ -232,7 +1309,7 @@ impl SimpleComponent for MainWindow {
     }
 
     fn init(
-        text: Self::Init,
+        request: Self::Init,
         root: Self::Root,
         sender: ComponentSender<Self>,
     ) -> ComponentParts<Self> {
@@ -240,53 +1317,121 @@ impl SimpleComponent for MainWindow {
         let style_scheme = sourceview5::StyleSchemeManager::default().scheme("Adwaita-dark");
         let buffer = sourceview5::Buffer::new(None);
         buffer.set_style_scheme(style_scheme.as_ref());
+        let source_view = sourceview5::View::with_buffer(&buffer);
 
-        let mut model = MainWindow {
-            text,
+        let follow_action =
+            gtk4::gio::SimpleAction::new_stateful("follow", None, &false.to_variant());
+
+        let model = MainWindow {
+            window: root.clone(),
             line: 1,
             column: 1,
             char_count: 0,
             current_file: None,
-            search_bar: ui::search::SearchBar::builder().launch(buffer.clone()),
+            search_bar: ui::search::SearchBar::builder()
+                .launch(source_view.clone())
+                .forward(sender.input_sender(), |msg| match msg {
+                    ui::search::SearchBarOutput::FindInFiles(query) => AppMsg::SearchInFiles(query),
+                    ui::search::SearchBarOutput::ReplaceInFiles(query, replacement) => {
+                        AppMsg::ReplaceInFiles(query, replacement)
+                    }
+                }),
+            go_to_line: ui::go_to_line::GoToLine::builder().launch(()).forward(
+                sender.input_sender(),
+                |msg| match msg {
+                    ui::go_to_line::GoToLineOutput::GoTo(target) => AppMsg::GoTo(target),
+                    ui::go_to_line::GoToLineOutput::Cancelled => AppMsg::FocusView,
+                },
+            ),
+            find_in_files: ui::find_in_files::FindInFiles::builder()
+                .launch(())
+                .forward(sender.input_sender(), |msg| match msg {
+                    ui::find_in_files::FindInFilesOutput::OpenMatch { path, line, column } => {
+                        AppMsg::OpenMatch(path, line, column)
+                    }
+                    ui::find_in_files::FindInFilesOutput::FilesChanged(paths) => {
+                        AppMsg::FilesChanged(paths)
+                    }
+                }),
+            hex_view: ui::hex_view::HexView::builder().launch(()).forward(
+                sender.input_sender(),
+                |msg| match msg {
+                    ui::hex_view::HexViewOutput::OpenAsText => AppMsg::OpenAsText,
+                },
+            ),
+            pending_cursor: None,
             buffer: buffer.clone(),
+            source_view,
             is_dirty: false,
+            needs_save: false,
+            close_state: CloseState::default(),
             file_hash: None,
+            file_monitor: None,
+            disk_stamp: None,
+            disk_change: None,
+            format: file_format::FileFormat::default(),
+            lossy_load: false,
+            mixed_line_endings: false,
+            binary: false,
+            operation: None,
+            large_file: false,
+            large_file_notice_dismissed: false,
+            following: false,
+            follower: None,
+            follow_check_scheduled: false,
+            follow_action,
         };
 
-        model.search_bar.detach_runtime();
-
         let search_bar = model.search_bar.widget();
+        let go_to_line = model.go_to_line.widget();
+        let find_in_files = model.find_in_files.widget();
+        let hex_view = model.hex_view.widget();
         let buffer = &model.buffer;
+        let source_view = &model.source_view;
 
         let widgets = view_output!();
-        widgets.source_view.set_buffer(Some(&model.buffer));
 
         {
+            // Runs on every keystroke, so nothing here may look at the whole
+            // text; the statistics wait until typing pauses
             let sender_clone = sender.clone();
+            let statistics_timeout: Rc<Cell<Option<gtk::glib::SourceId>>> = Rc::default();
             buffer.connect_changed(move |buffer| {
-                let text = buffer.text(&buffer.start_iter(), &buffer.end_iter(), false);
-                let char_count = text.as_str().chars().count() as i32;
-
                 let cursor_iter = buffer.iter_at_mark(&buffer.get_insert());
                 let line = cursor_iter.line() + 1;
                 let column = cursor_iter.line_offset() + 1;
+                sender_clone.input(AppMsg::UpdateCursorPos(line, column));
 
-                sender_clone.input(AppMsg::UpdateCursorPos(line, column, char_count));
-                sender_clone.input(AppMsg::TextChanged(text.to_owned().into()));
+                if let Some(timeout) = statistics_timeout.take() {
+                    timeout.remove();
+                }
+                let sender = sender_clone.clone();
+                let pending = statistics_timeout.clone();
+                statistics_timeout.set(Some(gtk::glib::timeout_add_local_once(
+                    STATISTICS_DELAY,
+                    move || {
+                        pending.set(None);
+                        sender.input(AppMsg::UpdateStatistics);
+                    },
+                )));
             });
         }
 
         {
             let sender_clone = sender.clone();
-            buffer.connect_mark_set(move |buffer, iter, mark| {
-                if mark.name().as_deref() == Some("insert") {
-                    let text = buffer.text(&buffer.start_iter(), &buffer.end_iter(), false);
-                    let char_count = text.as_str().chars().count() as i32;
+            buffer.connect_modified_changed(move |_| {
+                sender_clone.input(AppMsg::ModifiedChanged);
+            });
+        }
 
+        {
+            let sender_clone = sender.clone();
+            buffer.connect_mark_set(move |_, iter, mark| {
+                if mark.name().as_deref() == Some("insert") {
                     let line = iter.line() + 1;
                     let column = iter.line_offset() + 1;
 
-                    sender_clone.input(AppMsg::UpdateCursorPos(line, column, char_count));
+                    sender_clone.input(AppMsg::UpdateCursorPos(line, column));
                 }
             });
         }
@@ -298,7 +1443,8 @@ impl SimpleComponent for MainWindow {
         shortcut!("<Primary>o" => Open);
         shortcut!("<Primary>s" => Save);
         shortcut!("<Primary><Shift>s" => SaveAs);
-        shortcut!("<Primary>q|<Primary>w" => Quit);
+        shortcut!("<Primary>w" => Close);
+        shortcut!("<Primary>q" => Quit);
         shortcut!("<Primary>equal" => ZoomIn => {
             println!("Zoom in");
         });
@@ -306,6 +1452,8 @@ impl SimpleComponent for MainWindow {
             println!("Zoom out");
         });
         shortcut!("<Primary>f" => Find);
+        shortcut!("<Primary><Shift>f" => ToggleFindInFiles);
+        shortcut!("<Primary>l" => GoToLine);
 
         let sender_idk = sender.clone();
         let action_idk = gtk4::gio::SimpleAction::new("idk", None);
@@ -328,17 +1476,94 @@ impl SimpleComponent for MainWindow {
         });
         shortcutman.actions.add_action(&action_selectstylescheme);
 
-        widgets.source_view.add_controller(shortcutman.shortcut_ctl);
+        let action_backups = gtk4::gio::SimpleAction::new_stateful(
+            "backups",
+            None,
+            &settings::Settings::load().backup.enabled.to_variant(),
+        );
+        action_backups.connect_activate(move |action, _| {
+            // Other windows may have changed it since
+            let mut settings = settings::Settings::load();
+            settings.backup.enabled = !settings.backup.enabled;
+            settings.save();
+            action.set_state(&settings.backup.enabled.to_variant());
+        });
+        shortcutman.actions.add_action(&action_backups);
+
+        let sender_follow = sender.clone();
+        model.follow_action.connect_activate(move |_, _| {
+            sender_follow.input(AppMsg::ToggleFollow);
+        });
+        shortcutman.actions.add_action(&model.follow_action);
+
+        let sender_reopen_encoding = sender.clone();
+        let action_reopen_encoding =
+            gtk4::gio::SimpleAction::new("reopen-encoding", Some(gtk::glib::VariantTy::STRING));
+        action_reopen_encoding.connect_activate(move |_, param| {
+            if let Some(format) = param
+                .and_then(|param| param.str())
+                .and_then(file_format::FileFormat::from_id)
+            {
+                sender_reopen_encoding.input(AppMsg::ReopenWithEncoding(format));
+            }
+        });
+        shortcutman.actions.add_action(&action_reopen_encoding);
+
+        let sender_save_encoding = sender.clone();
+        let action_save_encoding =
+            gtk4::gio::SimpleAction::new("save-encoding", Some(gtk::glib::VariantTy::STRING));
+        action_save_encoding.connect_activate(move |_, param| {
+            if let Some(format) = param
+                .and_then(|param| param.str())
+                .and_then(file_format::FileFormat::from_id)
+            {
+                sender_save_encoding.input(AppMsg::SaveWithEncoding(format));
+            }
+        });
+        shortcutman.actions.add_action(&action_save_encoding);
+
+        let sender_line_ending = sender.clone();
+        let action_line_ending =
+            gtk4::gio::SimpleAction::new("line-ending", Some(gtk::glib::VariantTy::STRING));
+        action_line_ending.connect_activate(move |_, param| {
+            if let Some(line_ending) = param
+                .and_then(|param| param.str())
+                .and_then(file_format::LineEnding::from_name)
+            {
+                sender_line_ending.input(AppMsg::SetLineEnding(line_ending));
+            }
+        });
+        shortcutman.actions.add_action(&action_line_ending);
+
+        let line_ending_popover = gtk::PopoverMenu::from_model(Some(&build_line_ending_menu()));
+        line_ending_popover.set_parent(&widgets.line_ending_button);
+        widgets.line_ending_button.connect_clicked({
+            let line_ending_popover = line_ending_popover.clone();
+            move |_| line_ending_popover.popup()
+        });
+        widgets
+            .line_ending_button
+            .connect_destroy(move |_| line_ending_popover.unparent());
+
+        let encoding_popover = gtk::PopoverMenu::from_model(Some(&build_encoding_menu()));
+        encoding_popover.set_parent(&widgets.encoding_button);
+        widgets.encoding_button.connect_clicked({
+            let encoding_popover = encoding_popover.clone();
+            move |_| encoding_popover.popup()
+        });
+        widgets
+            .encoding_button
+            .connect_destroy(move |_| encoding_popover.unparent());
+
+        model.source_view.add_controller(shortcutman.shortcut_ctl);
         widgets
             .main_window
             .insert_action_group("app", Some(&shortcutman.actions));
 
-        main_application().connect_open(move |app, files, _| {
-            app.activate();
-            if let Some(file_path) = files.first().and_then(|file| file.path()) {
-                sender.input(AppMsg::LoadBuffer(file_path));
-            }
-        });
+        windows::register(&widgets.main_window, sender.input_sender().clone());
+        if let Some(request) = request {
+            sender.input(AppMsg::OpenFile(request));
+        }
 
         ComponentParts { model, widgets }
     }
@@ -351,50 +1576,179 @@ impl SimpleComponent for MainWindow {
                     .send(ui::search::SearchBarMsg::Trigger)
                     .unwrap();
             }
-            AppMsg::TextChanged(text) => {
-                self.text = text;
-                // Mark buffer as dirty if we hashed the current buffer
-                // in memory, and the hash doesn't match the currently
-                // stored one
-                if let Some(stored_hash) = self.file_hash {
-                    // println!("Calculating hash...");
-                    // println!("Stored hash: {}", stored_hash);
-                    // println!("Current hash: {}", self.hash_buffer_data());
-
-                    // todo:
-                    let current_hash = self.hash_buffer_data();
-                    self.is_dirty = stored_hash != current_hash;
+            AppMsg::GoToLine => {
+                self.go_to_line
+                    .sender()
+                    .send(ui::go_to_line::GoToLineMsg::Show)
+                    .unwrap();
+            }
+            AppMsg::GoTo(target) => {
+                let (line, column) = target.resolve(self.line, self.buffer.line_count());
+                self.go_to(line, column);
+            }
+            AppMsg::FocusView => {
+                self.source_view.grab_focus();
+            }
+            AppMsg::ToggleFindInFiles => {
+                self.find_in_files
+                    .sender()
+                    .send(ui::find_in_files::FindInFilesMsg::Toggle)
+                    .unwrap();
+            }
+            AppMsg::SearchInFiles(query) => {
+                self.find_in_files
+                    .sender()
+                    .send(ui::find_in_files::FindInFilesMsg::Search(
+                        query,
+                        self.default_search_folder(),
+                    ))
+                    .unwrap();
+            }
+            AppMsg::ReplaceInFiles(query, replacement) => {
+                self.find_in_files
+                    .sender()
+                    .send(ui::find_in_files::FindInFilesMsg::PreviewReplace(
+                        query,
+                        replacement,
+                        self.default_search_folder(),
+                    ))
+                    .unwrap();
+            }
+            AppMsg::FilesChanged(paths) => {
+                // Pick up the new content, unless there's unsaved work in the buffer
+                if let Some(file_path) = &self.current_file {
+                    if paths.contains(file_path) && !self.is_dirty {
+                        sender.input(AppMsg::LoadBuffer(file_path.clone()));
+                    }
+                }
+            }
+            AppMsg::OpenFile(request) => {
+                if !self.is_reusable() {
+                    windows::open_new_window(Some(request));
+                } else {
+                    if let Some(waiter) = request.waiter {
+                        windows::hold_until_closed(&self.window, waiter);
+                    }
+                    self.pending_cursor = request.position;
+                    // Started right away, so the window is busy for the
+                    // next file sent to it
+                    self.load_buffer(request.path, None, false, &sender);
+                }
+            }
+            AppMsg::OpenText(decoded, waiter) => {
+                if !self.is_reusable() {
+                    windows::open_new_window(None).emit(AppMsg::OpenText(decoded, waiter));
+                    return;
+                }
+                if let Some(waiter) = waiter {
+                    windows::hold_until_closed(&self.window, waiter);
+                }
+                // Saved the way it was piped in, like a file that was opened
+                self.format = decoded.format;
+                self.lossy_load = decoded.lossy;
+                self.mixed_line_endings = decoded.mixed_line_endings;
+                let text = decoded.text;
+                let (content_type, _) = gio::content_type_guess(None::<&str>, text.as_bytes());
+                let lang = sourceview5::LanguageManager::default()
+                    .guess_language(None::<&str>, Some(content_type.as_str()));
+                self.set_large_file(&text);
+                sender.input(AppMsg::SetLanguage(lang));
+                sender.input(AppMsg::SetBufferData(text));
+                // Nothing on disk holds this text yet, so closing asks before
+                // throwing it away. Set right away, so the window isn't
+                // reused for the next document sent to it.
+                self.needs_save = true;
+                self.update_dirty();
+            }
+            AppMsg::MarkUnsaved => {
+                self.needs_save = true;
+                self.update_dirty();
+            }
+            AppMsg::ModifiedChanged => self.update_dirty(),
+            AppMsg::OpenMatch(file_path, line, column) => {
+                if self.current_file.as_ref() == Some(&file_path) {
+                    self.go_to(line, column);
+                } else if self.is_dirty {
+                    // Keep the unsaved changes, like opening from the command line
+                    windows::open_new_window(Some(cli::OpenRequest {
+                        path: file_path,
+                        position: Some((line, column)),
+                        waiter: None,
+                    }));
                 } else {
-                    self.is_dirty = true;
+                    self.pending_cursor = Some((line, column));
+                    sender.input(AppMsg::LoadBuffer(file_path));
                 }
             }
-            AppMsg::UpdateCursorPos(line, column, char_count) => {
+            AppMsg::UpdateCursorPos(line, column) => {
                 self.line = line;
                 self.column = column;
-                self.char_count = char_count;
             }
-            // Set content to buffer
-            AppMsg::SetBufferData(content) => {
-                self.text = content.clone();
-                // hash the buffer
-                self.buffer.set_text(&content);
-                // hash the buffer
-                self.file_hash = Some(self.hash_buffer_data());
+            AppMsg::UpdateStatistics => {
+                if !self.large_file {
+                    // Kept up to date by the buffer, no need to walk the text
+                    self.char_count = self.buffer.char_count();
+                }
             }
+            // Set content to buffer
+            AppMsg::SetBufferData(content) => self.set_buffer_text(&content),
             // Load file to buffer
-            AppMsg::LoadBuffer(file_path) => {
-                let Ok(content) = std::fs::read(&file_path) else {
+            AppMsg::LoadBuffer(file_path) => self.load_buffer(file_path, None, false, &sender),
+            AppMsg::ReopenWithEncoding(format) => {
+                if self.current_file.is_none() {
+                    return;
+                }
+                if !self.is_dirty {
+                    sender.input(AppMsg::ReloadWithEncoding(format.encoding));
+                    return;
+                }
+                let alert = gtk::AlertDialog::builder()
+                    .message("Unsaved changes")
+                    .detail(
+                        "Reopening the file with another encoding discards your unsaved changes.",
+                    )
+                    .buttons(vec!["Cancel", "Reopen"])
+                    .cancel_button(0)
+                    .default_button(0)
+                    .modal(true)
+                    .build();
+                alert.choose(
+                    Some(&self.window),
+                    None::<&gio::Cancellable>,
+                    move |response| {
+                        if let Ok(1) = response {
+                            sender.input(AppMsg::ReloadWithEncoding(format.encoding));
+                        }
+                    },
+                );
+            }
+            AppMsg::ReloadWithEncoding(encoding) => {
+                if let Some(file_path) = self.current_file.clone() {
+                    self.pending_cursor = Some((self.line, self.column));
+                    self.load_buffer(file_path, Some(encoding), false, &sender);
+                }
+            }
+            AppMsg::SaveWithEncoding(format) => {
+                if self.binary {
                     return;
+                }
+                // The document only takes the new format once it's saved in it
+                let format = file_format::FileFormat {
+                    line_ending: self.format.line_ending,
+                    ..format
                 };
-                let content = String::from_utf8_lossy(&content).into_owned();
-                self.current_file = Some(file_path.clone());
-                sender.input(AppMsg::SetBufferData(content));
-                println!("File opened successfully: {}", file_path.display());
-                // Mark buffer as clean until changes are made
-                self.is_dirty = false;
-                // Set text highlighting
-                let lang = self.guess_language_from_file();
-                sender.input(AppMsg::SetLanguage(lang));
+                self.save(format, sender);
+            }
+            AppMsg::SetLineEnding(line_ending) => {
+                if self.binary {
+                    return;
+                }
+                // Picking one by hand settles mixed line endings too
+                if line_ending != self.format.line_ending || self.mixed_line_endings {
+                    self.format.line_ending = line_ending;
+                    self.mixed_line_endings = false;
+                    sender.input(AppMsg::MarkUnsaved);
+                }
             }
             AppMsg::Open => {
                 let file_filter = gtk::FileFilter::new();
@@ -426,55 +1780,9 @@ impl SimpleComponent for MainWindow {
                 );
             }
 
-            AppMsg::Save => {
-                if let Some(file_path) = &self.current_file {
-                    let content = self
-                        .buffer
-                        .text(&self.buffer.start_iter(), &self.buffer.end_iter(), false)
-                        .to_string();
-                    sender.input(AppMsg::SaveBuffer(file_path.clone(), content));
-                } else {
-                    sender.input(AppMsg::SaveAs);
-                }
-            }
-
-            AppMsg::SaveAs => {
-                let file_filter = gtk::FileFilter::new();
-                file_filter.add_mime_type("text/*");
-                file_filter.set_name(Some("Text files"));
-                file_filter.add_pattern("*.txt");
-
-                let file_chooser = gtk::FileDialog::builder()
-                    // .filters(&[&file_filter])
-                    // .filter(&file_filter)
-                    // .action(gtk::FileChooserAction::Save)
-                    // .name("Save File")
-                    // .modal(true)
-                    .title("Save as...")
-                    .initial_name(&self.default_file_name())
-                    .build();
-
-                // let sender = sender.clone();
-                let model_buffer = self.buffer.clone();
-                file_chooser.save(
-                    None::<&gtk::Window>,
-                    None::<&gio::Cancellable>,
-                    move |res| {
-                        if let Ok(file) = res {
-                            if let Some(file_path) = file.path() {
-                                let content = model_buffer
-                                    .text(
-                                        &model_buffer.start_iter(),
-                                        &model_buffer.end_iter(),
-                                        false,
-                                    )
-                                    .to_string();
-                                sender.input(AppMsg::SaveBuffer(file_path, content));
-                            }
-                        }
-                    },
-                );
-            }
+            AppMsg::Save => self.save(self.format, sender),
+            AppMsg::SaveAs => self.save_as(self.format, sender),
+            AppMsg::SaveAsWithFormat(format) => self.save_as(format, sender),
             // AppMsg::SaveContent(content) => {
             //     if let Some(file_path) = &self.current_file {
             //         match std::fs::write(file_path, &content) {
@@ -488,66 +1796,277 @@ impl SimpleComponent for MainWindow {
             //     }
             // }
             AppMsg::SetLanguage(lang) => {
+                // Highlighting is the slowest part of a large file
+                let lang = lang.filter(|_| !self.large_file);
                 self.buffer.set_language(lang.as_ref());
             }
-            AppMsg::SaveBuffer(file_path, content) => {
+            AppMsg::LeaveLargeFileMode => {
+                self.large_file = false;
+                sender.input(AppMsg::SetLanguage(self.guess_language_from_file()));
+                sender.input(AppMsg::UpdateStatistics);
+            }
+            AppMsg::DismissLargeFileNotice => self.large_file_notice_dismissed = true,
+            AppMsg::ToggleFollow => {
+                if self.following {
+                    self.set_following(false);
+                    return;
+                }
+                if self.current_file.is_none() || self.binary {
+                    return;
+                }
+                if !self.is_dirty {
+                    sender.input(AppMsg::StartFollowing);
+                    return;
+                }
+                let alert = gtk::AlertDialog::builder()
+                    .message("Unsaved changes")
+                    .detail("Following the file reloads it from disk, which discards your unsaved changes.")
+                    .buttons(vec!["Cancel", "Follow"])
+                    .cancel_button(0)
+                    .default_button(0)
+                    .modal(true)
+                    .build();
+                alert.choose(
+                    Some(&self.window),
+                    None::<&gio::Cancellable>,
+                    move |response| {
+                        if let Ok(1) = response {
+                            sender.input(AppMsg::StartFollowing);
+                        }
+                    },
+                );
+            }
+            AppMsg::StartFollowing => {
+                // The load would be refused while another load or save runs
+                if self.operation.is_some() {
+                    println!("Still loading or saving, not following the file");
+                    return;
+                }
+                if let Some(file_path) = self.current_file.clone() {
+                    self.set_following(true);
+                    self.pending_cursor = None;
+                    self.load_buffer(file_path, Some(self.format.encoding), false, &sender);
+                }
+            }
+            AppMsg::FollowFile => {
+                if self.following {
+                    self.schedule_follow_check(&sender);
+                }
+            }
+            AppMsg::CheckFollowedFile => {
+                self.follow_check_scheduled = false;
+                if self.following {
+                    self.follow_file(&sender);
+                }
+            }
+            AppMsg::SaveBuffer(file_path, content, format) => {
+                let (encoded, unmappable) = format.encode(&content);
+                if unmappable || self.lossy_load || self.mixed_line_endings {
+                    self.confirm_lossy_save(file_path, encoded, format, unmappable, sender);
+                } else {
+                    sender.input(AppMsg::WriteFile(file_path, encoded, format));
+                }
+            }
+            AppMsg::WriteFile(file_path, content, format) => {
+                if self.operation.is_some() {
+                    println!(
+                        "Still loading or saving, not saving {}",
+                        file_path.display()
+                    );
+                    self.abort_close();
+                    return;
+                }
                 println!("Saving buffer to file: {}", file_path.display());
-                match std::fs::write(&file_path, &content) {
+                let progress = self.start_operation(file_io::FileOperation::Save, &sender);
+                let backup = settings::Settings::load().backup;
+                let sender = sender.clone();
+                gtk::glib::spawn_future_local(async move {
+                    let path = file_path.clone();
+                    // Shared so it's still there to retry with if the save
+                    // thread panics
+                    let content = Arc::new(content);
+                    let written = content.clone();
+                    let result = gio::spawn_blocking(move || {
+                        file_io::save_file_with_progress(&path, &written, Some(&backup), &progress)
+                    })
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(file_io::FileError::new(
+                            file_io::FileOperation::Save,
+                            &file_path,
+                            std::io::Error::other("Writing the file crashed"),
+                        ))
+                    });
+                    let content = Arc::try_unwrap(content).unwrap_or_else(|c| c.to_vec());
+                    sender.input(AppMsg::FileWritten(file_path, content, format, result));
+                });
+            }
+            AppMsg::FileWritten(file_path, content, format, result) => {
+                self.finish_operation();
+                match result {
                     Ok(_) => {
                         println!("File saved successfully at: {}", file_path.display());
+                        // Save As moves the document to the new file
+                        let renamed = self.current_file.as_ref() != Some(&file_path);
+                        self.format = format;
+                        self.bind_to_file(file_path, &sender);
+                        if renamed {
+                            sender.input(AppMsg::SetLanguage(self.guess_language_from_file()));
+                        }
+                        self.lossy_load = false;
+                        self.mixed_line_endings = false;
+                        if self.close_state == CloseState::SavingBeforeClose {
+                            self.close_state = CloseState::Open;
+                            sender.input(AppMsg::Close);
+                        }
+                    }
+                    Err(e) if e.is_cancelled() => {
+                        println!("Cancelled saving {}", file_path.display());
+                        self.abort_close();
                     }
                     Err(e) => {
                         println!("Error saving file: {}", e);
+                        self.show_save_error(&e, file_path, content, format, sender);
                     }
                 }
             }
-            AppMsg::Quit => {
-                println!("Quitting...");
-
-                println!("Dirty buffer: {:?}", self.is_dirty);
-
-                if self.is_dirty {
-                    let alert = gtk::AlertDialog::builder()
-                        // .title("Unsaved changes")
-                        .message("Unsaved changes")
-                        .detail("You have unsaved changes. Do you want to save before quitting?")
-                        // .buttons(&[
-                        //     ("Save", gtk::ResponseType::Yes),
-                        //     ("Don't Save", gtk::ResponseType::No),
-                        //     ("Cancel", gtk::ResponseType::Cancel),
-                        // ])
-                        // .buttons(gtk::ButtonsType::YesNoCancel)
-                        .buttons(vec!["Close without saving", "Cancel", "Save"])
-                        .cancel_button(2)
-                        .default_button(3)
-                        .modal(true)
-                        .build();
-                    alert.choose(
-                        None::<&gtk::Window>,
-                        None::<&gio::Cancellable>,
-                        move |response| {
-                            if let Ok(res) = response {
-                                match res {
-                                    0 => {
-                                        std::process::exit(0);
-                                    }
-                                    1 => {
-                                        // Cancel
-                                    }
-                                    2 => {
-                                        sender.input(AppMsg::Save);
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        },
-                    );
-                    // alert.show(None::<&gtk::Window>);
+            AppMsg::SaveCancelled => self.abort_close(),
+            AppMsg::FileLoaded(file_path, result) => self.finish_load(file_path, result, &sender),
+            // The view reads the progress itself, this only refreshes it
+            AppMsg::ShowProgress => {}
+            AppMsg::CancelOperation => {
+                if let Some(operation) = &self.operation {
+                    operation.progress.cancel();
+                }
+            }
+            AppMsg::CheckDiskFile => {
+                if self.following {
+                    self.schedule_follow_check(&sender);
+                    return;
+                }
+                // A save trips over its own temporary file and rename, and
+                // a load reads the file anyway. Both watch it afresh once
+                // they're done.
+                if self.operation.is_some() {
+                    return;
+                }
+                let Some(file_path) = &self.current_file else {
+                    return;
+                };
+                let stamp = file_stamp(file_path);
+                if stamp.is_none() && !file_path.exists() {
+                    self.disk_change = Some(DiskChange::Deleted);
+                } else if stamp == self.disk_stamp {
+                    self.disk_change = None;
+                } else if self.binary {
+                    // Nothing in the buffer to compare against
+                    self.changed_on_disk(&sender);
                 } else {
-                    std::process::exit(0);
+                    self.read_disk_file(DiskRead::Check, &sender);
+                }
+            }
+            AppMsg::DiskFileRead(file_path, purpose, stamp, result) => {
+                // Another file was opened in the meantime
+                if self.current_file.as_ref() != Some(&file_path) {
+                    return;
+                }
+                match purpose {
+                    DiskRead::Check => {
+                        // Changed again while being read, or saved or
+                        // reloaded since, which is checked on its own
+                        if self.operation.is_some()
+                            || self.following
+                            || file_stamp(&file_path) != stamp
+                        {
+                            return;
+                        }
+                        match result {
+                            Ok((hash, _)) if Some(hash) == self.file_hash => {
+                                // Touched, saved by us, or put back the way
+                                // it was
+                                self.disk_stamp = stamp;
+                                self.disk_change = None;
+                            }
+                            Ok(_) => self.changed_on_disk(&sender),
+                            Err(e) => println!("Error checking file: {}", e),
+                        }
+                    }
+                    DiskRead::KeepBuffer => {
+                        // The buffer is now unsaved compared to what's on
+                        // disk, if anything still is
+                        self.file_hash = result.ok().map(|(hash, _)| hash);
+                        if self.file_hash == Some(self.hash_buffer_data()) {
+                            self.buffer.set_modified(false);
+                        } else {
+                            self.needs_save = true;
+                        }
+                        self.update_dirty();
+                        self.disk_stamp = stamp;
+                    }
+                    DiskRead::Diff => match result {
+                        Ok((_, disk_content)) => self.show_disk_diff(&disk_content),
+                        Err(e) => self.show_load_error(&e),
+                    },
+                }
+            }
+            AppMsg::ReloadFromDisk => {
+                if let Some(file_path) = self.current_file.clone() {
+                    // Keep the encoding, which may have been picked by hand,
+                    // and show binary files in the hex view again
+                    self.pending_cursor = Some((self.line, self.column));
+                    let encoding = (!self.binary).then_some(self.format.encoding);
+                    self.load_buffer(file_path, encoding, false, &sender);
+                }
+            }
+            AppMsg::OpenAsText => {
+                if let Some(file_path) = self.current_file.clone() {
+                    self.load_buffer(file_path, None, true, &sender);
+                }
+            }
+            AppMsg::KeepBufferChanges => {
+                self.disk_change = None;
+                self.read_disk_file(DiskRead::KeepBuffer, &sender);
+            }
+            AppMsg::ShowDiskDiff => self.read_disk_file(DiskRead::Diff, &sender),
+            AppMsg::SaveAndClose => {
+                self.close_state = CloseState::SavingBeforeClose;
+                sender.input(AppMsg::Save);
+            }
+            AppMsg::Close => {
+                if !self.is_dirty {
+                    self.window.destroy();
+                    return;
                 }
 
-                // std::process::exit(0);
+                let alert = gtk::AlertDialog::builder()
+                    // .title("Unsaved changes")
+                    .message("Unsaved changes")
+                    .detail("You have unsaved changes. Do you want to save before closing?")
+                    // .buttons(&[
+                    //     ("Save", gtk::ResponseType::Yes),
+                    //     ("Don't Save", gtk::ResponseType::No),
+                    //     ("Cancel", gtk::ResponseType::Cancel),
+                    // ])
+                    // .buttons(gtk::ButtonsType::YesNoCancel)
+                    .buttons(vec!["Close without saving", "Cancel", "Save"])
+                    .cancel_button(1)
+                    .default_button(2)
+                    .modal(true)
+                    .build();
+                let window = self.window.clone();
+                alert.choose(
+                    Some(&self.window),
+                    None::<&gio::Cancellable>,
+                    move |response| match response {
+                        Ok(0) => window.destroy(),
+                        Ok(2) => sender.input(AppMsg::SaveAndClose),
+                        _ => windows::cancel_quit(),
+                    },
+                );
+            }
+            AppMsg::Quit => {
+                println!("Quitting...");
+                windows::quit();
             }
             AppMsg::Idk => {
                 println!("IDK clicked");
@@ -630,11 +2149,17 @@ fn build_menu() -> gio::Menu {
     file_menu.append_item(&gio::MenuItem::new(Some("Open"), Some("app.open")));
     file_menu.append_item(&gio::MenuItem::new(Some("Save"), Some("app.save")));
     file_menu.append_item(&gio::MenuItem::new(Some("Save As"), Some("app.saveas")));
-    enigmata_menu.append_item(&gio::MenuItem::new(Some("Exit"), Some("app.exit")));
+    file_menu.append_item(&gio::MenuItem::new(
+        Some("Keep Backups"),
+        Some("app.backups"),
+    ));
+    file_menu.append_item(&gio::MenuItem::new(Some("Close Window"), Some("app.close")));
+    enigmata_menu.append_item(&gio::MenuItem::new(Some("Quit"), Some("app.quit")));
     enigmata_menu.append_item(&gio::MenuItem::new(
         Some("Set Style Scheme"),
         Some("app.selectstylescheme"),
     ));
+    enigmata_menu.append_item(&gio::MenuItem::new(Some("Follow File"), Some("app.follow")));
 
     // file_menu.append_item(&gio::MenuItem::new(Some("Nothing yet..."), Some("app.idk")));
 
@@ -647,13 +2172,114 @@ fn build_menu() -> gio::Menu {
     menu
 }
 
+/// Menu of the encoding button in the status bar
+fn build_encoding_menu() -> gio::Menu {
+    let menu = gio::Menu::new();
+    let reopen_menu = gio::Menu::new();
+    let save_menu = gio::Menu::new();
+
+    for &(id, name) in file_format::ENCODINGS {
+        let reopen_item = gio::MenuItem::new(Some(name), None);
+        reopen_item
+            .set_action_and_target_value(Some("app.reopen-encoding"), Some(&id.to_variant()));
+        reopen_menu.append_item(&reopen_item);
+
+        let save_item = gio::MenuItem::new(Some(name), None);
+        save_item.set_action_and_target_value(Some("app.save-encoding"), Some(&id.to_variant()));
+        save_menu.append_item(&save_item);
+    }
+
+    menu.append_submenu(Some("Reopen with Encoding…"), &reopen_menu);
+    menu.append_submenu(Some("Save with Encoding…"), &save_menu);
+    menu
+}
+
+/// Menu of the line ending button in the status bar
+fn build_line_ending_menu() -> gio::Menu {
+    let menu = gio::Menu::new();
+    for (line_ending, label) in [
+        (file_format::LineEnding::Lf, "Convert to LF (Unix)"),
+        (file_format::LineEnding::CrLf, "Convert to CRLF (Windows)"),
+    ] {
+        let item = gio::MenuItem::new(Some(label), None);
+        item.set_action_and_target_value(
+            Some("app.line-ending"),
+            Some(&line_ending.name().to_variant()),
+        );
+        menu.append_item(&item);
+    }
+    menu
+}
+
 const APP_ID: &str = "com.fyralabs.Enigmata";
 use gtk4::glib::translate::FromGlibPtrNone;
 
+/// Whether another Enigmata already runs, to hand this invocation over to
+fn primary_instance_running() -> bool {
+    let Ok(connection) = gio::bus_get_sync(gio::BusType::Session, None::<&gio::Cancellable>) else {
+        return false;
+    };
+    connection
+        .call_sync(
+            Some("org.freedesktop.DBus"),
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "NameHasOwner",
+            Some(&(APP_ID,).to_variant()),
+            Some(gtk4::glib::VariantTy::new("(b)").unwrap()),
+            gio::DBusCallFlags::NONE,
+            -1,
+            None::<&gio::Cancellable>,
+        )
+        .ok()
+        .and_then(|reply| reply.get::<(bool,)>())
+        .is_some_and(|(running,)| running)
+}
+
+/// Read everything piped into the invocation's standard input, then open it
+/// as an untitled document
+fn read_stdin(command_line: &gio::ApplicationCommandLine, wait: bool) {
+    let Some(stdin) = command_line.stdin() else {
+        println!("No standard input to read from");
+        return;
+    };
+    // Holding the invocation keeps a remote caller's standard input open
+    let command_line = command_line.clone();
+    gtk::glib::spawn_future_local(async move {
+        let mut data = Vec::new();
+        loop {
+            match stdin
+                .read_bytes_future(64 * 1024, gtk::glib::Priority::DEFAULT)
+                .await
+            {
+                Ok(bytes) if bytes.is_empty() => break,
+                Ok(bytes) => data.extend_from_slice(&bytes),
+                Err(e) => {
+                    println!("Error reading standard input: {}", e);
+                    return;
+                }
+            }
+        }
+        let decoded = file_format::decode(&data, None);
+        windows::open_text(decoded, wait.then_some(command_line));
+    });
+}
+
 fn main() {
+    let mut flags = libhelium::gtk::gio::ApplicationFlags::HANDLES_OPEN
+        | libhelium::gtk::gio::ApplicationFlags::HANDLES_COMMAND_LINE;
+    // The primary instance runs until all of its windows are closed, files
+    // opened in it later included. Started by `--wait`, it keeps to itself
+    // instead, so it returns once the files it was given are closed.
+    let args: Vec<std::ffi::OsString> = std::env::args_os().skip(1).collect();
+    let cwd = std::env::current_dir().unwrap_or_default();
+    if cli::CommandLine::parse(&args, &cwd).wait && !primary_instance_running() {
+        flags |= libhelium::gtk::gio::ApplicationFlags::NON_UNIQUE;
+    }
+
     let happ = libhelium::Application::builder()
         .application_id(APP_ID)
-        .flags(libhelium::gtk::gio::ApplicationFlags::HANDLES_OPEN)
+        .flags(flags)
         .default_accent_color(unsafe {
             &libhelium::RGBColor::from_glib_none(std::ptr::from_mut(
                 &mut libhelium::ffi::HeRGBColor {
@@ -665,16 +2291,45 @@ fn main() {
         })
         .build();
 
-    happ.connect_open(move |_app, files, _| {
-        // let sender = app.sen
-        for file in files {
-            if let Some(file) = file.path() {
-                println!("Opening file: {}", file.display());
+    happ.connect_open(move |app, files, _| {
+        app.activate();
+        windows::open_files(
+            files
+                .iter()
+                .filter_map(|file| file.path())
+                .map(|path| cli::OpenRequest {
+                    path,
+                    position: None,
+                    waiter: None,
+                })
+                .collect(),
+        );
+    });
+
+    happ.connect_command_line(move |app, command_line| {
+        let args = command_line.arguments();
+        let cwd = command_line.cwd().unwrap_or_default();
+        let mut parsed = cli::CommandLine::parse(args.get(1..).unwrap_or_default(), &cwd);
+        if parsed.wait {
+            // The invocation returns once every window holding it is closed.
+            // For the process itself that's when the app quits, which it
+            // does then as it runs on its own, see `main`.
+            for request in &mut parsed.files {
+                request.waiter = Some(command_line.clone());
             }
         }
+        // Makes sure the first window exists
+        app.activate();
+        windows::open_files(parsed.files);
+        if parsed.stdin {
+            read_stdin(command_line, parsed.wait);
+        }
+        0
     });
 
-    let app = RelmApp::from_app(happ);
-    app.allow_multiple_instances(true);
-    app.run::<MainWindow>("".to_string());
+    // A single instance, so `--wait` invocations are handed over to the
+    // running one and wait on its windows
+    let app = RelmApp::from_app(happ).with_args(std::env::args().collect());
+    app.run::<MainWindow>(None);
+    windows::release_waiters();
 }
@@ -0,0 +1,376 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use gtk4::glib;
+
+use crate::search_history::SearchQuery;
+
+/// Files bigger than this are skipped, they're very unlikely to be text
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+/// Line previews are cut off after this many characters
+const MAX_PREVIEW_CHARS: usize = 200;
+
+/// A single match inside a file
+#[derive(Debug, Clone)]
+pub(crate) struct LineMatch {
+    /// Line of the match, starting at 1
+    pub(crate) line: i32,
+    /// Column of the match in characters, starting at 1
+    pub(crate) column: i32,
+    /// The line the match is on
+    pub(crate) preview: String,
+}
+
+/// Every match inside one file
+#[derive(Debug, Clone)]
+pub(crate) struct FileMatches {
+    pub(crate) path: PathBuf,
+    pub(crate) matches: Vec<LineMatch>,
+}
+
+/// A block of consecutive lines changed by replacing matches in it
+#[derive(Debug, Clone)]
+pub(crate) struct Hunk {
+    /// First line of the hunk, starting at 1
+    pub(crate) line: i32,
+    /// Byte range of the lines in the original file
+    pub(crate) range: Range<usize>,
+    pub(crate) old_text: String,
+    pub(crate) new_text: String,
+}
+
+/// Proposed changes to one file when replacing across files
+#[derive(Debug, Clone)]
+pub(crate) struct FileReplacement {
+    pub(crate) path: PathBuf,
+    /// Content of the file the hunks were computed from
+    pub(crate) original: String,
+    pub(crate) hunks: Vec<Hunk>,
+}
+
+/// Changes replacing across files would make, from [`replace_directory`]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReplacePreview {
+    /// Files with changes, sorted by path
+    pub(crate) files: Vec<FileReplacement>,
+    /// Files the changes couldn't be worked out for, with the reason
+    pub(crate) failures: Vec<(PathBuf, String)>,
+}
+
+impl FileReplacement {
+    /// The file's new content, with only the hunks for which `accept` returns true applied
+    pub(crate) fn apply(&self, mut accept: impl FnMut(usize) -> bool) -> String {
+        let mut content = String::with_capacity(self.original.len());
+        let mut last_end = 0;
+        for (i, hunk) in self.hunks.iter().enumerate() {
+            if !accept(i) {
+                continue;
+            }
+            content.push_str(&self.original[last_end..hunk.range.start]);
+            content.push_str(&hunk.new_text);
+            last_end = hunk.range.end;
+        }
+        content.push_str(&self.original[last_end..]);
+        content
+    }
+}
+
+/// Build a regex that matches `query` the same way `sourceview5::SearchContext` does
+pub(crate) fn build_regex(query: &SearchQuery) -> Result<glib::Regex, String> {
+    let mut pattern = if query.regex {
+        query.text.clone()
+    } else {
+        glib::Regex::escape_string(query.text.as_str()).to_string()
+    };
+    if query.whole_words {
+        pattern = format!(r"\b(?:{})\b", pattern);
+    }
+
+    let mut flags = glib::RegexCompileFlags::MULTILINE | glib::RegexCompileFlags::OPTIMIZE;
+    if !query.case_sensitive {
+        flags |= glib::RegexCompileFlags::CASELESS;
+    }
+
+    glib::Regex::new(&pattern, flags, glib::RegexMatchFlags::empty())
+        .map_err(|e| e.message().to_string())?
+        .ok_or_else(|| "Invalid pattern".to_string())
+}
+
+/// Every text file under `root` with its content, skipping files ignored by
+/// `.gitignore` and friends
+fn text_files(root: &Path) -> impl Iterator<Item = (PathBuf, String)> {
+    ignore::WalkBuilder::new(root)
+        .build()
+        .flatten()
+        .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+        .filter_map(|entry| {
+            let text = read_text_file(entry.path())?;
+            Some((entry.into_path(), text))
+        })
+}
+
+/// Search every text file under `root` for `query`
+///
+/// This reads every file, so run it off the main thread.
+pub(crate) fn search_directory(
+    root: &Path,
+    query: &SearchQuery,
+) -> Result<Vec<FileMatches>, String> {
+    let regex = build_regex(query)?;
+
+    let mut results: Vec<FileMatches> = text_files(root)
+        .filter_map(|(path, text)| {
+            let matches = find_matches(&regex, &text);
+            (!matches.is_empty()).then_some(FileMatches { path, matches })
+        })
+        .collect();
+
+    results.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(results)
+}
+
+/// Work out the changes replacing `query` with `replacement` would make to
+/// every text file under `root`, without writing anything
+///
+/// This reads every file, so run it off the main thread.
+pub(crate) fn replace_directory(
+    root: &Path,
+    query: &SearchQuery,
+    replacement: &str,
+) -> Result<ReplacePreview, String> {
+    let regex = build_regex(query)?;
+    if query.regex {
+        glib::Regex::check_replacement(replacement).map_err(|e| e.message().to_string())?;
+    }
+
+    let mut preview = ReplacePreview::default();
+    for (path, text) in text_files(root) {
+        match find_hunks(&regex, &text, replacement, query.regex) {
+            Ok(hunks) if hunks.is_empty() => {}
+            Ok(hunks) => preview.files.push(FileReplacement {
+                path,
+                original: text,
+                hunks,
+            }),
+            Err(e) => preview.failures.push((path, e)),
+        }
+    }
+
+    preview.files.sort_by(|a, b| a.path.cmp(&b.path));
+    preview.failures.sort();
+    Ok(preview)
+}
+
+/// Read a file if it looks like UTF-8 text
+pub(crate) fn read_text_file(path: &Path) -> Option<String> {
+    let metadata = std::fs::metadata(path).ok()?;
+    if metadata.len() > MAX_FILE_SIZE {
+        return None;
+    }
+    let content = std::fs::read(path).ok()?;
+    // NUL bytes don't show up in text files
+    if content.contains(&0) {
+        return None;
+    }
+    String::from_utf8(content).ok()
+}
+
+/// Find every match of `regex` in `text`
+pub(crate) fn find_matches(regex: &glib::Regex, text: &str) -> Vec<LineMatch> {
+    let text = glib::GString::from(text);
+    let Ok(info) = regex.match_full(text.as_gstr(), 0, glib::RegexMatchFlags::empty()) else {
+        return Vec::new();
+    };
+
+    // Byte offsets of the start of every line
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+
+    let mut matches = Vec::new();
+    while info.matches() {
+        let Some((start, _)) = info.fetch_pos(0) else {
+            break;
+        };
+        let start = start as usize;
+        let line = line_starts.partition_point(|&line_start| line_start <= start) - 1;
+        let line_start = line_starts[line];
+        let line_end = line_starts
+            .get(line + 1)
+            .map(|&next| next - 1)
+            .unwrap_or(text.len());
+        let line_text = &text[line_start..line_end];
+
+        matches.push(LineMatch {
+            line: line as i32 + 1,
+            column: text[line_start..start].chars().count() as i32 + 1,
+            preview: line_text
+                .trim_end_matches('\r')
+                .chars()
+                .take(MAX_PREVIEW_CHARS)
+                .collect(),
+        });
+
+        if !info.next().unwrap_or(false) {
+            break;
+        }
+    }
+    matches
+}
+
+/// Replace every match of `regex` in `text`, grouping the changes into hunks
+/// of whole lines
+///
+/// With `expand_references`, `\1`-style backreferences in `replacement` are
+/// expanded, like the search bar does with regex enabled. Fails if they
+/// can't be, as replacing only part of the matches would be wrong.
+pub(crate) fn find_hunks(
+    regex: &glib::Regex,
+    text: &str,
+    replacement: &str,
+    expand_references: bool,
+) -> Result<Vec<Hunk>, String> {
+    let gtext = glib::GString::from(text);
+    let Ok(info) = regex.match_full(gtext.as_gstr(), 0, glib::RegexMatchFlags::empty()) else {
+        return Ok(Vec::new());
+    };
+
+    // Lines touched by matches, with the replacements inside them
+    let mut groups: Vec<(Range<usize>, Vec<(Range<usize>, String)>)> = Vec::new();
+    while info.matches() {
+        let Some((start, end)) = info.fetch_pos(0) else {
+            break;
+        };
+        let (start, end) = (start as usize, end as usize);
+        let new = if expand_references {
+            info.expand_references(replacement)
+                .map_err(|e| e.message().to_string())?
+                .map(String::from)
+                .unwrap_or_default()
+        } else {
+            replacement.to_string()
+        };
+
+        let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = text[end..].find('\n').map_or(text.len(), |i| end + i);
+        match groups.last_mut() {
+            Some((lines, edits)) if line_start <= lines.end => {
+                lines.end = lines.end.max(line_end);
+                edits.push((start..end, new));
+            }
+            _ => groups.push((line_start..line_end, vec![(start..end, new)])),
+        }
+
+        if !info.next().unwrap_or(false) {
+            break;
+        }
+    }
+
+    let mut hunks = Vec::new();
+    let mut line = 1;
+    let mut counted_up_to = 0;
+    for (lines, edits) in groups {
+        line += text[counted_up_to..lines.start].matches('\n').count() as i32;
+        counted_up_to = lines.start;
+
+        let old_text = &text[lines.clone()];
+        let mut new_text = String::with_capacity(old_text.len());
+        let mut last_end = lines.start;
+        for (range, new) in edits {
+            new_text.push_str(&text[last_end..range.start]);
+            new_text.push_str(&new);
+            last_end = range.end;
+        }
+        new_text.push_str(&text[last_end..lines.end]);
+
+        if new_text != old_text {
+            hunks.push(Hunk {
+                line,
+                range: lines,
+                old_text: old_text.to_string(),
+                new_text,
+            });
+        }
+    }
+    Ok(hunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(text: &str, regex: bool) -> SearchQuery {
+        SearchQuery {
+            text: text.to_string(),
+            regex,
+            case_sensitive: true,
+            whole_words: false,
+        }
+    }
+
+    fn hunks(query: &SearchQuery, text: &str, replacement: &str) -> Vec<Hunk> {
+        let regex = build_regex(query).unwrap();
+        find_hunks(&regex, text, replacement, query.regex).unwrap()
+    }
+
+    #[test]
+    fn finds_whole_words_ignoring_case() {
+        let query = SearchQuery {
+            whole_words: true,
+            case_sensitive: false,
+            ..query("foo", false)
+        };
+        let regex = build_regex(&query).unwrap();
+        let matches = find_matches(&regex, "Foo food\nbar foo");
+        let positions: Vec<_> = matches.iter().map(|m| (m.line, m.column)).collect();
+        assert_eq!(positions, vec![(1, 1), (2, 5)]);
+        assert_eq!(matches[1].preview, "bar foo");
+    }
+
+    #[test]
+    fn groups_matches_by_line() {
+        let text = "foo bar\nbaz\nfoo foo\n\nend foo";
+        let hunks = hunks(&query("foo", false), text, "qux");
+
+        let lines: Vec<_> = hunks.iter().map(|hunk| hunk.line).collect();
+        assert_eq!(lines, vec![1, 3, 5]);
+        let old: Vec<_> = hunks.iter().map(|hunk| hunk.old_text.as_str()).collect();
+        assert_eq!(old, vec!["foo bar", "foo foo", "end foo"]);
+        let new: Vec<_> = hunks.iter().map(|hunk| hunk.new_text.as_str()).collect();
+        assert_eq!(new, vec!["qux bar", "qux qux", "end qux"]);
+    }
+
+    #[test]
+    fn treats_replacement_literally_without_regex() {
+        let hunks = hunks(&query("a.b", false), "a.b axb", r"\0");
+        assert_eq!(hunks.len(), 1);
+        assert_eq!(hunks[0].new_text, r"\0 axb");
+    }
+
+    #[test]
+    fn expands_references_with_regex() {
+        let hunks = hunks(&query(r"(\w+)@(\w+)", true), "a@b c@d", r"\2@\1");
+        assert_eq!(hunks.len(), 1);
+        assert_eq!(hunks[0].new_text, "b@a d@c");
+    }
+
+    #[test]
+    fn skips_lines_that_stay_the_same() {
+        assert!(hunks(&query("foo", false), "foo\nfoo", "foo").is_empty());
+    }
+
+    #[test]
+    fn applies_only_accepted_hunks() {
+        let original = "one\ntwo\nthree\ntwo\n".to_string();
+        let replacement = FileReplacement {
+            path: PathBuf::from("file.txt"),
+            hunks: hunks(&query("two", false), &original, "2"),
+            original,
+        };
+        assert_eq!(replacement.hunks.len(), 2);
+
+        assert_eq!(replacement.apply(|_| true), "one\n2\nthree\n2\n");
+        assert_eq!(replacement.apply(|i| i == 1), "one\ntwo\nthree\n2\n");
+        assert_eq!(replacement.apply(|_| false), replacement.original);
+    }
+}
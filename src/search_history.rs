@@ -0,0 +1,228 @@
+use std::cell::RefCell;
+use std::path::PathBuf;
+
+use gtk4::glib;
+
+/// How many queries and replacements to remember
+const MAX_ENTRIES: usize = 50;
+
+/// A search query, together with the options it was searched with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SearchQuery {
+    pub(crate) text: String,
+    pub(crate) regex: bool,
+    pub(crate) case_sensitive: bool,
+    pub(crate) whole_words: bool,
+}
+
+thread_local! {
+    /// The history every window shares, so none of them overwrites what the
+    /// others added when saving it
+    static SHARED: RefCell<SearchHistory> = RefCell::new(SearchHistory::load());
+}
+
+/// Run `f` on the history shared by every window, loaded on first use
+pub(crate) fn with_shared<R>(f: impl FnOnce(&mut SearchHistory) -> R) -> R {
+    SHARED.with_borrow_mut(f)
+}
+
+/// Previously used search queries and replacements, most recent first
+///
+/// Stored as a key file in the user data directory, so it's kept across sessions
+#[derive(Debug)]
+pub(crate) struct SearchHistory {
+    pub(crate) queries: Vec<SearchQuery>,
+    pub(crate) replacements: Vec<String>,
+    /// Key file the history is kept in
+    path: PathBuf,
+}
+
+impl SearchHistory {
+    fn default_path() -> PathBuf {
+        glib::user_data_dir()
+            .join("enigmata")
+            .join("search-history.ini")
+    }
+
+    /// Load the history from disk, starting empty if there's none yet
+    fn load() -> Self {
+        Self::load_from(Self::default_path())
+    }
+
+    /// Load the history from the key file at `path`, which it's also saved to
+    fn load_from(path: PathBuf) -> Self {
+        let key_file = glib::KeyFile::new();
+        if key_file
+            .load_from_file(&path, glib::KeyFileFlags::NONE)
+            .is_err()
+        {
+            return Self {
+                queries: Vec::new(),
+                replacements: Vec::new(),
+                path,
+            };
+        }
+
+        let queries = key_file
+            .groups()
+            .iter()
+            .filter(|group| group.starts_with("query-"))
+            .filter_map(|group| {
+                Some(SearchQuery {
+                    text: key_file.string(group, "text").ok()?.into(),
+                    regex: key_file.boolean(group, "regex").unwrap_or_default(),
+                    case_sensitive: key_file
+                        .boolean(group, "case_sensitive")
+                        .unwrap_or_default(),
+                    whole_words: key_file.boolean(group, "whole_words").unwrap_or_default(),
+                })
+            })
+            .collect();
+
+        let replacements = key_file
+            .string_list("replace", "history")
+            .map(|list| list.iter().map(|r| r.to_string()).collect())
+            .unwrap_or_default();
+
+        Self {
+            queries,
+            replacements,
+            path,
+        }
+    }
+
+    /// Write the history to disk
+    fn save(&self) {
+        let key_file = glib::KeyFile::new();
+        for (i, query) in self.queries.iter().enumerate() {
+            let group = format!("query-{:02}", i);
+            key_file.set_string(&group, "text", &query.text);
+            key_file.set_boolean(&group, "regex", query.regex);
+            key_file.set_boolean(&group, "case_sensitive", query.case_sensitive);
+            key_file.set_boolean(&group, "whole_words", query.whole_words);
+        }
+        let replacements: Vec<&str> = self.replacements.iter().map(String::as_str).collect();
+        key_file.set_string_list("replace", "history", &replacements);
+
+        let path = &self.path;
+        if let Some(dir) = path.parent() {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                println!("Error creating data directory: {}", e);
+                return;
+            }
+        }
+        if let Err(e) = key_file.save_to_file(path) {
+            println!("Error saving search history: {}", e);
+        }
+    }
+
+    /// Add a query to the top of the history, moving it there if it's already in it
+    pub(crate) fn add_query(&mut self, query: SearchQuery) {
+        if query.text.is_empty() || self.queries.first() == Some(&query) {
+            return;
+        }
+        self.queries.retain(|q| q.text != query.text);
+        self.queries.insert(0, query);
+        self.queries.truncate(MAX_ENTRIES);
+        self.save();
+    }
+
+    /// Add a replacement to the top of the history, moving it there if it's already in it
+    pub(crate) fn add_replacement(&mut self, replacement: &str) {
+        if replacement.is_empty()
+            || self.replacements.first().map(String::as_str) == Some(replacement)
+        {
+            return;
+        }
+        self.replacements.retain(|r| r != replacement);
+        self.replacements.insert(0, replacement.to_string());
+        self.replacements.truncate(MAX_ENTRIES);
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_io::tests::TempDir;
+
+    fn query(text: &str, regex: bool) -> SearchQuery {
+        SearchQuery {
+            text: text.to_string(),
+            regex,
+            case_sensitive: false,
+            whole_words: false,
+        }
+    }
+
+    #[test]
+    fn moves_repeated_query_to_front() {
+        let temp = TempDir::new("history-queries");
+        let mut history = SearchHistory::load_from(temp.0.join("history.ini"));
+
+        history.add_query(query("one", false));
+        history.add_query(query("two", false));
+        history.add_query(query("", false));
+        history.add_query(query("one", false));
+        assert_eq!(
+            history.queries,
+            vec![query("one", false), query("two", false)]
+        );
+
+        // The same text with other options takes the old entry's place
+        history.add_query(query("two", true));
+        assert_eq!(
+            history.queries,
+            vec![query("two", true), query("one", false)]
+        );
+    }
+
+    #[test]
+    fn moves_repeated_replacement_to_front() {
+        let temp = TempDir::new("history-replacements");
+        let mut history = SearchHistory::load_from(temp.0.join("history.ini"));
+
+        for replacement in ["one", "two", "", "one"] {
+            history.add_replacement(replacement);
+        }
+        assert_eq!(history.replacements, vec!["one", "two"]);
+    }
+
+    #[test]
+    fn keeps_only_most_recent_entries() {
+        let temp = TempDir::new("history-cap");
+        let mut history = SearchHistory::load_from(temp.0.join("history.ini"));
+
+        for i in 0..MAX_ENTRIES + 5 {
+            history.add_query(query(&i.to_string(), false));
+            history.add_replacement(&i.to_string());
+        }
+        assert_eq!(history.queries.len(), MAX_ENTRIES);
+        assert_eq!(history.replacements.len(), MAX_ENTRIES);
+        let newest = (MAX_ENTRIES + 4).to_string();
+        assert_eq!(history.queries[0].text, newest);
+        assert_eq!(history.replacements[0], newest);
+        assert_eq!(history.replacements[MAX_ENTRIES - 1], "5");
+    }
+
+    #[test]
+    fn saves_and_loads_again() {
+        let temp = TempDir::new("history-round-trip");
+        let path = temp.0.join("data").join("history.ini");
+        let mut history = SearchHistory::load_from(path.clone());
+        history.add_query(query("plain", false));
+        history.add_query(SearchQuery {
+            text: r"fn (\w+)".to_string(),
+            regex: true,
+            case_sensitive: true,
+            whole_words: true,
+        });
+        history.add_replacement("with_underscore");
+        history.add_replacement("$1; and more");
+
+        let loaded = SearchHistory::load_from(path);
+
+        assert_eq!(loaded.queries, history.queries);
+        assert_eq!(loaded.replacements, history.replacements);
+    }
+}
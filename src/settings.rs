@@ -0,0 +1,205 @@
+use std::path::PathBuf;
+
+use gtk4::glib;
+
+/// How many timestamped backups of each file to keep by default
+const DEFAULT_BACKUPS_KEPT: usize = 10;
+/// Files this many bytes or larger open in large file mode by default
+const DEFAULT_LARGE_FILE_SIZE: u64 = 5 * 1024 * 1024;
+/// Files with a line this many bytes or longer open in large file mode
+/// by default
+const DEFAULT_LARGE_FILE_LINE_LENGTH: usize = 10_000;
+
+/// What to keep of a file's previous contents when saving over it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BackupSettings {
+    pub(crate) enabled: bool,
+    /// Folder to keep timestamped backups in, or `None` for a single `file~`
+    /// next to the file
+    pub(crate) directory: Option<PathBuf>,
+    /// How many backups of each file to keep in `directory`, 0 keeps them all
+    pub(crate) keep: usize,
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: None,
+            keep: DEFAULT_BACKUPS_KEPT,
+        }
+    }
+}
+
+/// When to open files in large file mode, which turns off features that
+/// make editing them slow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LargeFileSettings {
+    /// Size in bytes from which a file is large, 0 never makes one large
+    pub(crate) size: u64,
+    /// Line length in bytes from which a file is large, 0 never makes one
+    /// large
+    pub(crate) line_length: usize,
+}
+
+impl Default for LargeFileSettings {
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_LARGE_FILE_SIZE,
+            line_length: DEFAULT_LARGE_FILE_LINE_LENGTH,
+        }
+    }
+}
+
+impl LargeFileSettings {
+    /// Whether `text` is large enough for large file mode
+    pub(crate) fn applies_to(&self, text: &str) -> bool {
+        self.is_large_size(text.len() as u64)
+            || text.split('\n').any(|line| self.is_long_line(line.len()))
+    }
+
+    /// Whether a document of `size` bytes is large enough for large file mode
+    pub(crate) fn is_large_size(&self, size: u64) -> bool {
+        self.size > 0 && size >= self.size
+    }
+
+    /// Whether a line of `len` bytes is long enough for large file mode
+    pub(crate) fn is_long_line(&self, len: usize) -> bool {
+        self.line_length > 0 && len >= self.line_length
+    }
+}
+
+/// User settings
+///
+/// Stored as a key file in the user config directory, which can also be edited by hand:
+///
+/// ```ini
+/// [backup]
+/// enabled=true
+/// directory=/home/me/.local/share/enigmata/backups
+/// keep=10
+///
+/// [large_file]
+/// size=5242880
+/// line_length=10000
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Settings {
+    pub(crate) backup: BackupSettings,
+    pub(crate) large_file: LargeFileSettings,
+}
+
+impl Settings {
+    fn path() -> PathBuf {
+        glib::user_config_dir()
+            .join("enigmata")
+            .join("settings.ini")
+    }
+
+    /// Load the settings from disk, using the defaults for anything not set
+    pub(crate) fn load() -> Self {
+        let key_file = glib::KeyFile::new();
+        if key_file
+            .load_from_file(Self::path(), glib::KeyFileFlags::NONE)
+            .is_err()
+        {
+            return Self::default();
+        }
+
+        let defaults = BackupSettings::default();
+        let backup = BackupSettings {
+            enabled: key_file
+                .boolean("backup", "enabled")
+                .unwrap_or(defaults.enabled),
+            directory: key_file
+                .string("backup", "directory")
+                .ok()
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| PathBuf::from(dir.as_str())),
+            keep: key_file
+                .uint64("backup", "keep")
+                .map(|keep| keep as usize)
+                .unwrap_or(defaults.keep),
+        };
+
+        let defaults = LargeFileSettings::default();
+        let large_file = LargeFileSettings {
+            size: key_file
+                .uint64("large_file", "size")
+                .unwrap_or(defaults.size),
+            line_length: key_file
+                .uint64("large_file", "line_length")
+                .map(|length| length as usize)
+                .unwrap_or(defaults.line_length),
+        };
+
+        Self { backup, large_file }
+    }
+
+    /// Write the settings to disk, keeping anything else in the file
+    pub(crate) fn save(&self) {
+        let path = Self::path();
+        let key_file = glib::KeyFile::new();
+        let _ = key_file.load_from_file(
+            &path,
+            glib::KeyFileFlags::KEEP_COMMENTS | glib::KeyFileFlags::KEEP_TRANSLATIONS,
+        );
+
+        key_file.set_boolean("backup", "enabled", self.backup.enabled);
+        match &self.backup.directory {
+            Some(dir) => key_file.set_string("backup", "directory", &dir.to_string_lossy()),
+            None => {
+                let _ = key_file.remove_key("backup", "directory");
+            }
+        }
+        key_file.set_uint64("backup", "keep", self.backup.keep as u64);
+        key_file.set_uint64("large_file", "size", self.large_file.size);
+        key_file.set_uint64(
+            "large_file",
+            "line_length",
+            self.large_file.line_length as u64,
+        );
+
+        if let Some(dir) = path.parent() {
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                println!("Error creating config directory: {}", e);
+                return;
+            }
+        }
+        if let Err(e) = key_file.save_to_file(&path) {
+            println!("Error saving settings: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_file_mode_applies_to_big_or_long_lined_text() {
+        let settings = LargeFileSettings {
+            size: 20,
+            line_length: 8,
+        };
+        assert!(!settings.applies_to("short\nlines\n"));
+        assert!(settings.applies_to("one long line\n"));
+        assert!(settings.applies_to("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n"));
+        assert!(settings.is_large_size(20));
+        assert!(!settings.is_large_size(19));
+        assert!(settings.is_long_line(8));
+        assert!(!settings.is_long_line(7));
+    }
+
+    #[test]
+    fn zero_turns_a_check_off() {
+        let settings = LargeFileSettings {
+            size: 0,
+            line_length: 0,
+        };
+        assert!(!settings.applies_to(&"x".repeat(100_000)));
+        assert!(!settings.is_large_size(u64::MAX));
+        assert!(!settings.is_long_line(usize::MAX));
+        assert!(!settings.applies_to(""));
+    }
+}
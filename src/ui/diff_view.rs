@@ -0,0 +1,54 @@
+use relm4::gtk;
+use relm4::gtk::prelude::*;
+use sourceview5::prelude::*;
+
+/// Unified diff going from `old` to `new`, with the given names in the header
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    similar::TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(old_name, new_name)
+        .to_string()
+}
+
+/// Show `diff` in a read-only window on top of `parent`
+pub fn show(
+    parent: &impl IsA<gtk::Window>,
+    title: &str,
+    diff: &str,
+    style_scheme: Option<&sourceview5::StyleScheme>,
+) {
+    let buffer = sourceview5::Buffer::new(None);
+    buffer.set_language(
+        sourceview5::LanguageManager::default()
+            .language("diff")
+            .as_ref(),
+    );
+    buffer.set_style_scheme(style_scheme);
+    buffer.set_text(if diff.is_empty() {
+        "No differences"
+    } else {
+        diff
+    });
+
+    let view = sourceview5::View::builder()
+        .buffer(&buffer)
+        .editable(false)
+        .monospace(true)
+        .show_line_numbers(true)
+        .build();
+    let scrolled = gtk::ScrolledWindow::builder()
+        .child(&view)
+        .vexpand(true)
+        .hexpand(true)
+        .build();
+
+    let window = gtk::Window::builder()
+        .title(title)
+        .transient_for(parent)
+        .default_width(900)
+        .default_height(600)
+        .child(&scrolled)
+        .build();
+    window.present();
+}
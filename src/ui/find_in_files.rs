@@ -0,0 +1,602 @@
+use std::path::PathBuf;
+
+use relm4::gtk::prelude::*;
+use relm4::gtk::{gio, glib};
+use relm4::{gtk, RelmWidgetExt};
+
+use crate::file_io;
+use crate::project_search::{self, FileMatches, FileReplacement, ReplacePreview};
+use crate::search_history::SearchQuery;
+use crate::settings::Settings;
+
+/// Panel listing the matches of a search across every file in a folder, or
+/// the changes replacing them would make
+#[derive(Debug, Default)]
+pub struct FindInFiles {
+    results_list: gtk4::ListBox,
+    visible: bool,
+
+    /// Folder being searched
+    root: Option<PathBuf>,
+    /// Query of the last search
+    query: Option<SearchQuery>,
+    /// What to replace matches with, when previewing a replace across files
+    replacement: Option<String>,
+    /// Results of the last search, sorted by path
+    results: Vec<FileMatches>,
+    /// Proposed changes of the last replace preview, sorted by path
+    replacements: Vec<FileReplacement>,
+    /// Whether each hunk of `replacements` will be applied
+    accepted: Vec<Vec<bool>>,
+    /// Check buttons of every hunk, so a whole file can be (un)ticked at once
+    hunk_checks: Vec<Vec<gtk4::CheckButton>>,
+    /// Files the last replace couldn't preview or write, with the reason
+    failures: Vec<(PathBuf, String)>,
+    /// Where each row of `results_list` jumps to when activated, as a file
+    /// and 1-based line and column
+    rows: Vec<Option<(PathBuf, i32, i32)>>,
+    /// Summary of the last search, or its error
+    status: String,
+    /// Bumped on every search, so results of an outdated search are dropped
+    generation: u64,
+    /// A search or replace is running
+    searching: bool,
+}
+
+#[derive(Debug)]
+pub enum FindInFilesMsg {
+    Hide,
+    Toggle,
+    /// Search for a query, in the given folder unless one has been picked already
+    Search(SearchQuery, Option<PathBuf>),
+    /// Preview replacing a query, in the given folder unless one has been picked already
+    PreviewReplace(SearchQuery, String, Option<PathBuf>),
+    /// Run the last search again
+    Refresh,
+    /// Open a dialog to pick the folder to search in
+    SelectFolder,
+    SetFolder(PathBuf),
+    SearchFinished(u64, Result<Vec<FileMatches>, String>),
+    ReplacePreviewFinished(u64, Result<ReplacePreview, String>),
+    /// Tick or untick a single hunk of the replace preview
+    SetHunkAccepted(usize, usize, bool),
+    /// Tick or untick every hunk of a file in the replace preview
+    SetFileAccepted(usize, bool),
+    /// Write the ticked hunks to disk
+    ApplyReplacements,
+    ReplacementsApplied(Vec<(PathBuf, Result<(), String>)>),
+    /// A row in the results list was activated
+    ActivateRow(i32),
+}
+
+#[derive(Debug)]
+pub enum FindInFilesOutput {
+    /// Open a file and move the cursor to a match
+    OpenMatch {
+        path: PathBuf,
+        line: i32,
+        column: i32,
+    },
+    /// Files were changed on disk by replacing across files
+    FilesChanged(Vec<PathBuf>),
+}
+
+/// Put `prefix` in front of every line of `text`, like a diff does
+fn prefix_lines(text: &str, prefix: &str) -> String {
+    text.lines()
+        .map(|line| format!("{}{}", prefix, line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl FindInFiles {
+    fn root_display(&self) -> String {
+        self.root
+            .as_ref()
+            .map(|root| root.display().to_string())
+            .unwrap_or_default()
+    }
+
+    fn start_search(&mut self, sender: &relm4::ComponentSender<Self>) {
+        let (Some(root), Some(query)) = (self.root.clone(), self.query.clone()) else {
+            return;
+        };
+
+        self.generation += 1;
+        self.searching = true;
+        self.status = format!("Searching {}…", root.display());
+
+        let generation = self.generation;
+        let sender = sender.clone();
+        if let Some(replacement) = self.replacement.clone() {
+            let handle = gio::spawn_blocking(move || {
+                project_search::replace_directory(&root, &query, &replacement)
+            });
+            glib::spawn_future_local(async move {
+                let results = handle
+                    .await
+                    .unwrap_or_else(|_| Err("Search failed unexpectedly".to_string()));
+                sender.input(FindInFilesMsg::ReplacePreviewFinished(generation, results));
+            });
+        } else {
+            let handle =
+                gio::spawn_blocking(move || project_search::search_directory(&root, &query));
+            glib::spawn_future_local(async move {
+                let results = handle
+                    .await
+                    .unwrap_or_else(|_| Err("Search failed unexpectedly".to_string()));
+                sender.input(FindInFilesMsg::SearchFinished(generation, results));
+            });
+        }
+    }
+
+    /// Write every file with at least one ticked hunk
+    fn apply_replacements(&mut self, sender: &relm4::ComponentSender<Self>) {
+        let writes: Vec<(PathBuf, String, String)> = self
+            .replacements
+            .iter()
+            .zip(&self.accepted)
+            .filter(|(_, accepted)| accepted.contains(&true))
+            .map(|(file, accepted)| {
+                (
+                    file.path.clone(),
+                    file.original.clone(),
+                    file.apply(|i| accepted[i]),
+                )
+            })
+            .collect();
+        if writes.is_empty() {
+            return;
+        }
+
+        self.generation += 1;
+        self.searching = true;
+        self.status = format!("Replacing in {} files…", writes.len());
+
+        // Every file counts as failed if the thread writing them panics
+        let paths: Vec<PathBuf> = writes.iter().map(|(path, _, _)| path.clone()).collect();
+        let backup = Settings::load().backup;
+        let handle = gio::spawn_blocking(move || {
+            writes
+                .into_iter()
+                .map(|(path, original, content)| {
+                    // Don't clobber changes made after the preview was computed
+                    let result = match std::fs::read_to_string(&path) {
+                        Ok(current) if current == original => {
+                            file_io::save_file(&path, content.as_bytes(), Some(&backup))
+                                .map_err(|e| e.reason())
+                        }
+                        Ok(_) => Err("the file changed since the preview".to_string()),
+                        Err(e) => Err(e.to_string()),
+                    };
+                    (path, result)
+                })
+                .collect::<Vec<_>>()
+        });
+        let sender = sender.clone();
+        glib::spawn_future_local(async move {
+            let results = handle.await.unwrap_or_else(|_| {
+                paths
+                    .into_iter()
+                    .map(|path| (path, Err("Replacing failed unexpectedly".to_string())))
+                    .collect()
+            });
+            sender.input(FindInFilesMsg::ReplacementsApplied(results));
+        });
+    }
+
+    /// Rebuild the results list, grouped by file
+    fn refresh_results_list(&mut self, sender: &relm4::ComponentSender<Self>) {
+        self.results_list.remove_all();
+        self.rows.clear();
+        self.hunk_checks.clear();
+
+        let root = self.root.clone().unwrap_or_default();
+        let relative = |path: &PathBuf| {
+            path.strip_prefix(&root)
+                .unwrap_or(path)
+                .display()
+                .to_string()
+        };
+
+        for (path, error) in &self.failures {
+            let row = gtk::Label::builder()
+                .label(format!("{}: {}", relative(path), error))
+                .xalign(0.0)
+                .css_classes(["error"])
+                .build();
+            self.results_list.append(&row);
+            self.rows.push(None);
+        }
+
+        for file in &self.results {
+            let header = gtk::Label::builder()
+                .label(format!("{} ({})", relative(&file.path), file.matches.len()))
+                .xalign(0.0)
+                .css_classes(["heading"])
+                .build();
+            self.results_list.append(&header);
+            // File headers jump to the first match
+            self.rows.push(
+                file.matches
+                    .first()
+                    .map(|m| (file.path.clone(), m.line, m.column)),
+            );
+
+            for line_match in &file.matches {
+                let row = gtk::Label::builder()
+                    .label(format!(
+                        "{}: {}",
+                        line_match.line,
+                        line_match.preview.trim()
+                    ))
+                    .xalign(0.0)
+                    .margin_start(16)
+                    .ellipsize(gtk::pango::EllipsizeMode::End)
+                    .css_classes(["monospace"])
+                    .build();
+                self.results_list.append(&row);
+                self.rows.push(Some((
+                    file.path.clone(),
+                    line_match.line,
+                    line_match.column,
+                )));
+            }
+        }
+
+        for (file_index, file) in self.replacements.iter().enumerate() {
+            let file_check = gtk::CheckButton::builder()
+                .label(format!("{} ({})", relative(&file.path), file.hunks.len()))
+                .active(self.accepted[file_index].contains(&true))
+                .css_classes(["heading"])
+                .build();
+            {
+                let sender = sender.clone();
+                file_check.connect_toggled(move |check| {
+                    sender.input(FindInFilesMsg::SetFileAccepted(
+                        file_index,
+                        check.is_active(),
+                    ));
+                });
+            }
+            self.results_list.append(&file_check);
+            self.rows.push(
+                file.hunks
+                    .first()
+                    .map(|hunk| (file.path.clone(), hunk.line, 1)),
+            );
+
+            let mut checks = Vec::new();
+            for (hunk_index, hunk) in file.hunks.iter().enumerate() {
+                let hunk_check = gtk::CheckButton::builder()
+                    .active(self.accepted[file_index][hunk_index])
+                    .valign(gtk::Align::Start)
+                    .build();
+                {
+                    let sender = sender.clone();
+                    hunk_check.connect_toggled(move |check| {
+                        sender.input(FindInFilesMsg::SetHunkAccepted(
+                            file_index,
+                            hunk_index,
+                            check.is_active(),
+                        ));
+                    });
+                }
+
+                let diff = gtk::Box::new(gtk::Orientation::Vertical, 0);
+                diff.append(
+                    &gtk::Label::builder()
+                        .label(format!("Line {}", hunk.line))
+                        .xalign(0.0)
+                        .css_classes(["dim-label"])
+                        .build(),
+                );
+                diff.append(
+                    &gtk::Label::builder()
+                        .label(prefix_lines(&hunk.old_text, "- "))
+                        .xalign(0.0)
+                        .css_classes(["monospace", "error"])
+                        .build(),
+                );
+                diff.append(
+                    &gtk::Label::builder()
+                        .label(prefix_lines(&hunk.new_text, "+ "))
+                        .xalign(0.0)
+                        .css_classes(["monospace", "success"])
+                        .build(),
+                );
+
+                let row = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+                row.set_margin_start(16);
+                row.append(&hunk_check);
+                row.append(&diff);
+                self.results_list.append(&row);
+                self.rows.push(Some((file.path.clone(), hunk.line, 1)));
+                checks.push(hunk_check);
+            }
+            self.hunk_checks.push(checks);
+        }
+    }
+}
+
+#[relm4::component(pub)]
+impl relm4::SimpleComponent for FindInFiles {
+    type Init = ();
+    type Input = FindInFilesMsg;
+    type Output = FindInFilesOutput;
+
+    view! {
+        gtk::Revealer {
+            #[watch]
+            set_reveal_child: model.visible,
+            set_transition_duration: 200,
+            set_transition_type: gtk::RevealerTransitionType::SlideUp,
+
+            gtk::Box {
+                set_orientation: gtk::Orientation::Vertical,
+                set_height_request: 240,
+
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Horizontal,
+                    set_spacing: 8,
+                    set_margin_all: 8,
+
+                    gtk::Label {
+                        set_hexpand: true,
+                        set_xalign: 0.0,
+                        set_ellipsize: gtk::pango::EllipsizeMode::Middle,
+                        #[watch]
+                        set_label: &model.status,
+                    },
+
+                    gtk::Spinner {
+                        #[watch]
+                        set_spinning: model.searching,
+                        #[watch]
+                        set_visible: model.searching,
+                    },
+
+                    gtk::Button {
+                        set_label: "Replace",
+                        set_css_classes: &["destructive-action"],
+                        set_tooltip_text: Some("Write the ticked changes to disk"),
+                        #[watch]
+                        set_visible: !model.replacements.is_empty(),
+                        #[watch]
+                        set_sensitive: !model.searching
+                            && model.accepted.iter().any(|file| file.contains(&true)),
+                        connect_clicked[sender] => move |_| {
+                            sender.input(FindInFilesMsg::ApplyReplacements);
+                        },
+                    },
+
+                    gtk::Button {
+                        set_icon_name: "folder-open-symbolic",
+                        set_tooltip_text: Some("Search in folder..."),
+                        connect_clicked[sender] => move |_| {
+                            sender.input(FindInFilesMsg::SelectFolder);
+                        },
+                    },
+
+                    gtk::Button {
+                        set_icon_name: "view-refresh-symbolic",
+                        set_tooltip_text: Some("Search again"),
+                        #[watch]
+                        set_sensitive: model.query.is_some() && !model.searching,
+                        connect_clicked[sender] => move |_| {
+                            sender.input(FindInFilesMsg::Refresh);
+                        },
+                    },
+
+                    gtk::Button {
+                        set_icon_name: "window-close-symbolic",
+                        set_tooltip_text: Some("Close"),
+                        connect_clicked[sender] => move |_| {
+                            sender.input(FindInFilesMsg::Hide);
+                        },
+                    },
+                },
+
+                gtk::ScrolledWindow {
+                    set_vexpand: true,
+                    set_policy: (gtk::PolicyType::Never, gtk::PolicyType::Automatic),
+
+                    #[local_ref]
+                    results_list -> gtk::ListBox {
+                        set_selection_mode: gtk::SelectionMode::Browse,
+                        set_activate_on_single_click: true,
+                        connect_row_activated[sender] => move |_, row| {
+                            sender.input(FindInFilesMsg::ActivateRow(row.index()));
+                        },
+                    },
+                },
+            },
+        }
+    }
+
+    fn init(
+        _init: Self::Init,
+        root: Self::Root,
+        sender: relm4::ComponentSender<Self>,
+    ) -> relm4::ComponentParts<Self> {
+        let model = Self {
+            status: "Search for something to find it in files".to_string(),
+            ..Default::default()
+        };
+
+        let results_list = &model.results_list;
+        let widgets = view_output!();
+        relm4::ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: relm4::ComponentSender<Self>) {
+        match msg {
+            FindInFilesMsg::Hide => self.visible = false,
+            FindInFilesMsg::Toggle => self.visible = !self.visible,
+            FindInFilesMsg::Search(query, default_root) => {
+                if self.root.is_none() {
+                    self.root = default_root;
+                }
+                self.query = Some(query);
+                self.replacement = None;
+                self.visible = true;
+                if self.root.is_some() {
+                    self.start_search(&sender);
+                } else {
+                    sender.input(FindInFilesMsg::SelectFolder);
+                }
+            }
+            FindInFilesMsg::PreviewReplace(query, replacement, default_root) => {
+                if self.root.is_none() {
+                    self.root = default_root;
+                }
+                self.query = Some(query);
+                self.replacement = Some(replacement);
+                self.visible = true;
+                if self.root.is_some() {
+                    self.start_search(&sender);
+                } else {
+                    sender.input(FindInFilesMsg::SelectFolder);
+                }
+            }
+            FindInFilesMsg::Refresh => self.start_search(&sender),
+            FindInFilesMsg::SelectFolder => {
+                let folder_chooser = gtk::FileDialog::builder().title("Search in Folder").build();
+                if let Some(root) = &self.root {
+                    folder_chooser.set_initial_folder(Some(&gio::File::for_path(root)));
+                }
+
+                folder_chooser.select_folder(
+                    None::<&gtk::Window>,
+                    None::<&gio::Cancellable>,
+                    move |res| {
+                        if let Ok(folder) = res {
+                            if let Some(path) = folder.path() {
+                                sender.input(FindInFilesMsg::SetFolder(path));
+                            }
+                        }
+                    },
+                );
+            }
+            FindInFilesMsg::SetFolder(path) => {
+                self.root = Some(path);
+                self.start_search(&sender);
+            }
+            FindInFilesMsg::SearchFinished(generation, results) => {
+                if generation != self.generation {
+                    return;
+                }
+                self.searching = false;
+                self.replacements.clear();
+                self.accepted.clear();
+                self.failures.clear();
+                match results {
+                    Ok(results) => {
+                        let match_count: usize = results.iter().map(|f| f.matches.len()).sum();
+                        self.status = format!(
+                            "{} matches in {} files under {}",
+                            match_count,
+                            results.len(),
+                            self.root_display()
+                        );
+                        self.results = results;
+                    }
+                    Err(e) => {
+                        self.status = format!("Search failed: {}", e);
+                        self.results.clear();
+                    }
+                }
+                self.refresh_results_list(&sender);
+            }
+            FindInFilesMsg::ReplacePreviewFinished(generation, preview) => {
+                if generation != self.generation {
+                    return;
+                }
+                self.searching = false;
+                self.results.clear();
+                self.failures.clear();
+                match preview {
+                    Ok(ReplacePreview {
+                        files: replacements,
+                        failures,
+                    }) => {
+                        let hunk_count: usize = replacements.iter().map(|f| f.hunks.len()).sum();
+                        self.status = format!(
+                            "{} changes in {} files under {}, untick the ones to skip",
+                            hunk_count,
+                            replacements.len(),
+                            self.root_display()
+                        );
+                        self.accepted = replacements
+                            .iter()
+                            .map(|file| vec![true; file.hunks.len()])
+                            .collect();
+                        self.replacements = replacements;
+                        self.failures = failures;
+                    }
+                    Err(e) => {
+                        self.status = format!("Search failed: {}", e);
+                        self.replacements.clear();
+                        self.accepted.clear();
+                    }
+                }
+                self.refresh_results_list(&sender);
+            }
+            FindInFilesMsg::SetHunkAccepted(file_index, hunk_index, accepted) => {
+                if let Some(hunk) = self
+                    .accepted
+                    .get_mut(file_index)
+                    .and_then(|file| file.get_mut(hunk_index))
+                {
+                    *hunk = accepted;
+                }
+            }
+            FindInFilesMsg::SetFileAccepted(file_index, accepted) => {
+                if let Some(file) = self.accepted.get_mut(file_index) {
+                    file.iter_mut().for_each(|hunk| *hunk = accepted);
+                }
+                for check in self.hunk_checks.get(file_index).into_iter().flatten() {
+                    check.set_active(accepted);
+                }
+            }
+            FindInFilesMsg::ApplyReplacements => self.apply_replacements(&sender),
+            FindInFilesMsg::ReplacementsApplied(results) => {
+                self.searching = false;
+                let (written, failed): (Vec<_>, Vec<_>) =
+                    results.into_iter().partition(|(_, result)| result.is_ok());
+                self.failures = failed
+                    .into_iter()
+                    .filter_map(|(path, result)| Some((path, result.err()?)))
+                    .collect();
+                self.status = if self.failures.is_empty() {
+                    format!("Replaced in {} files", written.len())
+                } else {
+                    format!(
+                        "Replaced in {} files, {} could not be written",
+                        written.len(),
+                        self.failures.len()
+                    )
+                };
+
+                // The preview is outdated now
+                self.replacements.clear();
+                self.accepted.clear();
+                self.refresh_results_list(&sender);
+
+                let _ = sender.output(FindInFilesOutput::FilesChanged(
+                    written.into_iter().map(|(path, _)| path).collect(),
+                ));
+            }
+            FindInFilesMsg::ActivateRow(index) => {
+                let Some(Some((path, line, column))) = self.rows.get(index as usize) else {
+                    return;
+                };
+                let _ = sender.output(FindInFilesOutput::OpenMatch {
+                    path: path.clone(),
+                    line: *line,
+                    column: *column,
+                });
+            }
+        }
+    }
+}
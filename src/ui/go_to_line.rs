@@ -0,0 +1,191 @@
+use relm4::gtk::prelude::*;
+use relm4::{gtk, RelmWidgetExt};
+
+/// Where to move the cursor to, as typed into the go to line entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineTarget {
+    /// `42` or `42:7`, a 1-based line and optionally a column
+    Line(i32, Option<i32>),
+    /// `+10` or `-10`, lines relative to the cursor
+    Relative(i32, Option<i32>),
+    /// `%50`, a percentage of the document
+    Percent(i32),
+}
+
+impl LineTarget {
+    /// Parse `42`, `42:7`, `+10`, `-10`, `+10:7` or `%50`
+    pub fn parse(input: &str) -> Option<Self> {
+        let input = input.trim();
+        if let Some(percent) = input.strip_prefix('%').or_else(|| input.strip_suffix('%')) {
+            return Some(Self::Percent(percent.trim().parse().ok()?));
+        }
+
+        let (line, column) = match input.split_once(':') {
+            Some((line, column)) => (line.trim(), Some(column.trim().parse().ok()?)),
+            None => (input, None),
+        };
+        if line.starts_with('+') || line.starts_with('-') {
+            Some(Self::Relative(line.parse().ok()?, column))
+        } else {
+            Some(Self::Line(line.parse().ok()?, column))
+        }
+    }
+
+    /// The 1-based line and column this points to, clamped to the document
+    pub fn resolve(self, current_line: i32, line_count: i32) -> (i32, i32) {
+        let (line, column) = match self {
+            Self::Line(line, column) => (line, column),
+            Self::Relative(offset, column) => (current_line.saturating_add(offset), column),
+            Self::Percent(percent) => {
+                let percent = percent.clamp(0, 100) as i64;
+                (((line_count as i64 * percent) / 100) as i32, None)
+            }
+        };
+        (line.clamp(1, line_count.max(1)), column.unwrap_or(1).max(1))
+    }
+}
+
+/// Small entry for jumping to a line, opened with Ctrl+L
+#[derive(Debug, Default)]
+pub struct GoToLine {
+    entry: gtk4::Entry,
+    revealer: gtk4::Revealer,
+}
+
+#[derive(Debug)]
+pub enum GoToLineMsg {
+    Show,
+    Hide,
+    /// The entry was activated
+    Submit,
+}
+
+#[derive(Debug)]
+pub enum GoToLineOutput {
+    GoTo(LineTarget),
+    /// The entry was closed without jumping anywhere
+    Cancelled,
+}
+
+#[relm4::component(pub)]
+impl relm4::SimpleComponent for GoToLine {
+    type Init = ();
+    type Input = GoToLineMsg;
+    type Output = GoToLineOutput;
+
+    view! {
+        gtk::Box {
+            set_halign: gtk::Align::Center,
+            set_valign: gtk::Align::Start,
+            #[local_ref]
+            revealer -> gtk::Revealer {
+                set_transition_duration: 200,
+                set_transition_type: gtk::RevealerTransitionType::SlideDown,
+
+                #[local_ref]
+                entry -> gtk::Entry {
+                    inline_css: "background-color: @surface_bright_bg_color; border-radius: 8px;",
+                    set_margin_all: 16,
+                    set_width_chars: 28,
+                    set_placeholder_text: Some("Line, line:column, +/-lines or %"),
+                    connect_activate[sender] => move |_| {
+                        sender.input(GoToLineMsg::Submit);
+                    },
+                    connect_changed => move |entry| {
+                        entry.remove_css_class("error");
+                    },
+                    add_controller = gtk::EventControllerKey {
+                        connect_key_pressed[sender] => move |_, key, _, _| {
+                            if key == gtk::gdk::Key::Escape {
+                                sender.input(GoToLineMsg::Hide);
+                                gtk::glib::Propagation::Stop
+                            } else {
+                                gtk::glib::Propagation::Proceed
+                            }
+                        },
+                    },
+                },
+            },
+        }
+    }
+
+    fn init(
+        _init: Self::Init,
+        root: Self::Root,
+        sender: relm4::ComponentSender<Self>,
+    ) -> relm4::ComponentParts<Self> {
+        let model = Self::default();
+
+        let revealer = &model.revealer;
+        let entry = &model.entry;
+        let widgets = view_output!();
+        relm4::ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: relm4::ComponentSender<Self>) {
+        match msg {
+            GoToLineMsg::Show => {
+                self.entry.set_text("");
+                self.revealer.set_reveal_child(true);
+                self.entry.grab_focus();
+            }
+            GoToLineMsg::Hide => {
+                self.revealer.set_reveal_child(false);
+                let _ = sender.output(GoToLineOutput::Cancelled);
+            }
+            GoToLineMsg::Submit => match LineTarget::parse(&self.entry.text()) {
+                Some(target) => {
+                    self.revealer.set_reveal_child(false);
+                    let _ = sender.output(GoToLineOutput::GoTo(target));
+                }
+                None => self.entry.add_css_class("error"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lines_and_columns() {
+        assert_eq!(LineTarget::parse("42"), Some(LineTarget::Line(42, None)));
+        assert_eq!(
+            LineTarget::parse(" 42:7 "),
+            Some(LineTarget::Line(42, Some(7)))
+        );
+        assert_eq!(
+            LineTarget::parse("+10"),
+            Some(LineTarget::Relative(10, None))
+        );
+        assert_eq!(
+            LineTarget::parse("-10:3"),
+            Some(LineTarget::Relative(-10, Some(3)))
+        );
+        assert_eq!(LineTarget::parse("%50"), Some(LineTarget::Percent(50)));
+        assert_eq!(LineTarget::parse("50%"), Some(LineTarget::Percent(50)));
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert_eq!(LineTarget::parse(""), None);
+        assert_eq!(LineTarget::parse("abc"), None);
+        assert_eq!(LineTarget::parse("12:"), None);
+        assert_eq!(LineTarget::parse("%"), None);
+    }
+
+    #[test]
+    fn resolves_within_document() {
+        assert_eq!(LineTarget::Line(42, Some(7)).resolve(1, 100), (42, 7));
+        assert_eq!(LineTarget::Line(500, None).resolve(1, 100), (100, 1));
+        assert_eq!(LineTarget::Line(0, Some(0)).resolve(1, 100), (1, 1));
+        assert_eq!(LineTarget::Relative(10, None).resolve(20, 100), (30, 1));
+        assert_eq!(LineTarget::Relative(-30, None).resolve(20, 100), (1, 1));
+        assert_eq!(LineTarget::Percent(50).resolve(1, 200), (100, 1));
+        assert_eq!(LineTarget::Percent(150).resolve(1, 200), (200, 1));
+        assert_eq!(LineTarget::Percent(0).resolve(1, 200), (1, 1));
+        // An empty buffer still has line 1
+        assert_eq!(LineTarget::Line(5, None).resolve(1, 0), (1, 1));
+    }
+}
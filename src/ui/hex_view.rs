@@ -0,0 +1,196 @@
+use relm4::gtk::prelude::*;
+use relm4::{gtk, RelmWidgetExt};
+
+/// Bytes shown on each line
+const BYTES_PER_LINE: usize = 16;
+/// Bytes in each group of hex digits
+const BYTES_PER_GROUP: usize = 8;
+/// Only this much of a file is shown, larger ones are cut off
+const MAX_SHOWN_BYTES: usize = 8 * 1024 * 1024;
+/// Width of the offset column, including the spaces after it
+const OFFSET_WIDTH: usize = 10;
+
+/// Render `bytes` as lines of offset, hex bytes and ASCII
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::with_capacity(bytes.len() / BYTES_PER_LINE * 80);
+    for (line, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        dump.push_str(&format!("{:08x}  ", line * BYTES_PER_LINE));
+        for i in 0..BYTES_PER_LINE {
+            if i > 0 && i % BYTES_PER_GROUP == 0 {
+                dump.push(' ');
+            }
+            match chunk.get(i) {
+                Some(byte) => dump.push_str(&format!("{:02x} ", byte)),
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push_str(" |");
+        dump.extend(chunk.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        dump.push_str("|\n");
+    }
+    dump
+}
+
+/// Column of the hex digits of the byte at `index` within its line
+fn hex_column(index: usize) -> usize {
+    OFFSET_WIDTH + index * 3 + index / BYTES_PER_GROUP
+}
+
+/// Parse an offset typed as decimal, or as hex with a `0x` prefix
+fn parse_offset(input: &str) -> Option<usize> {
+    let input = input.trim();
+    match input
+        .strip_prefix("0x")
+        .or_else(|| input.strip_prefix("0X"))
+    {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => input.parse().ok(),
+    }
+}
+
+/// Read-only hex and ASCII view of a binary file
+#[derive(Debug, Default)]
+pub struct HexView {
+    text_view: gtk4::TextView,
+    offset_entry: gtk4::Entry,
+    /// How many bytes the file has
+    len: usize,
+    /// Whether the file was too large to show all of it
+    truncated: bool,
+}
+
+#[derive(Debug)]
+pub enum HexViewMsg {
+    /// Show the bytes of a file
+    Show(Vec<u8>),
+    /// The offset entry was activated
+    GoToOffset,
+    OpenAsText,
+}
+
+#[derive(Debug)]
+pub enum HexViewOutput {
+    /// Open the file as text even though it looks binary
+    OpenAsText,
+}
+
+#[relm4::component(pub)]
+impl relm4::SimpleComponent for HexView {
+    type Init = ();
+    type Input = HexViewMsg;
+    type Output = HexViewOutput;
+
+    view! {
+        gtk::Box {
+            set_orientation: gtk::Orientation::Vertical,
+            set_vexpand: true,
+
+            gtk::Box {
+                set_orientation: gtk::Orientation::Horizontal,
+                set_spacing: 8,
+                set_margin_all: 8,
+
+                gtk::Label {
+                    set_hexpand: true,
+                    set_xalign: 0.0,
+                    set_wrap: true,
+                    #[watch]
+                    set_label: &if model.truncated {
+                        format!(
+                            "This file looks binary, it's shown read-only. Only the first {} of {} bytes are shown.",
+                            MAX_SHOWN_BYTES, model.len
+                        )
+                    } else {
+                        "This file looks binary, it's shown read-only so saving can't damage it.".to_string()
+                    },
+                },
+                #[local_ref]
+                offset_entry -> gtk::Entry {
+                    set_placeholder_text: Some("Go to offset"),
+                    set_width_chars: 14,
+                    connect_activate[sender] => move |_| {
+                        sender.input(HexViewMsg::GoToOffset);
+                    },
+                    connect_changed => move |entry| {
+                        entry.remove_css_class("error");
+                    },
+                },
+                gtk::Button {
+                    set_label: "Open as Text Anyway",
+                    connect_clicked[sender] => move |_| {
+                        sender.input(HexViewMsg::OpenAsText);
+                    },
+                },
+            },
+
+            gtk::ScrolledWindow {
+                set_vexpand: true,
+                set_hexpand: true,
+                #[local_ref]
+                text_view -> gtk::TextView {
+                    set_editable: false,
+                    set_monospace: true,
+                    set_cursor_visible: true,
+                    set_left_margin: 8,
+                },
+            },
+        }
+    }
+
+    fn init(
+        _init: Self::Init,
+        root: Self::Root,
+        sender: relm4::ComponentSender<Self>,
+    ) -> relm4::ComponentParts<Self> {
+        let model = Self::default();
+
+        let text_view = &model.text_view;
+        let offset_entry = &model.offset_entry;
+        let widgets = view_output!();
+        relm4::ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: relm4::ComponentSender<Self>) {
+        match msg {
+            HexViewMsg::Show(bytes) => {
+                self.len = bytes.len();
+                self.truncated = bytes.len() > MAX_SHOWN_BYTES;
+                let shown = &bytes[..bytes.len().min(MAX_SHOWN_BYTES)];
+                let buffer = self.text_view.buffer();
+                buffer.set_text(&hex_dump(shown));
+                buffer.place_cursor(&buffer.start_iter());
+                self.offset_entry.set_text("");
+            }
+            HexViewMsg::GoToOffset => {
+                let shown = self.len.min(MAX_SHOWN_BYTES);
+                let Some(offset) = parse_offset(&self.offset_entry.text()).filter(|&o| o < shown)
+                else {
+                    self.offset_entry.add_css_class("error");
+                    return;
+                };
+                let buffer = self.text_view.buffer();
+                let line = (offset / BYTES_PER_LINE) as i32;
+                let column = hex_column(offset % BYTES_PER_LINE) as i32;
+                // Select the byte's two hex digits
+                if let (Some(start), Some(end)) = (
+                    buffer.iter_at_line_offset(line, column),
+                    buffer.iter_at_line_offset(line, column + 2),
+                ) {
+                    buffer.select_range(&start, &end);
+                    self.text_view
+                        .scroll_to_mark(&buffer.get_insert(), 0.0, true, 0.0, 0.3);
+                    self.text_view.grab_focus();
+                }
+            }
+            HexViewMsg::OpenAsText => {
+                let _ = sender.output(HexViewOutput::OpenAsText);
+            }
+        }
+    }
+}
@@ -1 +1,5 @@
+pub mod diff_view;
+pub mod find_in_files;
+pub mod go_to_line;
+pub mod hex_view;
 pub mod search;
\ No newline at end of file
@@ -1,18 +1,79 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
 use relm4::gtk::prelude::*;
+use relm4::gtk::{gio, glib};
 use relm4::{gtk, RelmWidgetExt};
 use sourceview5::prelude::*;
+
+use crate::project_search;
+use crate::search_history::{self, SearchQuery};
+
+thread_local! {
+    /// Query and replacement history dropdowns, shared by every window's
+    /// search bar like the history itself
+    static HISTORY_MENUS: (gio::Menu, gio::Menu) = (gio::Menu::new(), gio::Menu::new());
+}
+
 #[derive(Debug, Default)]
 pub struct SearchBar {
     /// Search entry for searching and replacing text
     search_entry: gtk4::SearchEntry,
+    /// Entry holding the replacement text
+    replace_entry: gtk4::Entry,
     find_revealer: gtk4::Revealer,
 
+    /// The view the search context operates on, used to scroll to matches
+    source_view: sourceview5::View,
+
     /// GTKSourceView search context
     search_context: sourceview5::SearchContext,
+
+    /// Total number of matches, -1 while the buffer is still being scanned
+    match_count: i32,
+    /// Position of the selected match, 0 if the selection isn't a match
+    match_position: i32,
+    /// Error message of an invalid regex pattern
+    regex_error: Option<String>,
+    /// What the selected match turns into when replaced, shown below the replace entry
+    replacement_preview: String,
+
+    /// Restrict searching and replacing to `selection_marks`
+    in_selection: bool,
+    /// Start and end of the selection the bar was opened with. Marks move with
+    /// the text, so the range stays correct while replacing inside it.
+    selection_marks: Option<(gtk::TextMark, gtk::TextMark)>,
+
+    /// Position in the query history while browsing it with Up/Down
+    history_index: Option<usize>,
+    /// The buffer can't be edited for now, so replacing is turned off
+    read_only: bool,
+
+    /// The current query compiled to a regex, reused while it stays the same
+    regex_cache: Option<(SearchQuery, glib::Regex)>,
+    /// Matches inside the search region, reused until the query, the region
+    /// or the buffer changes
+    region_cache: Option<RegionMatches>,
+    /// Bumped on every change to the buffer, to tell when `region_cache` is
+    /// outdated
+    buffer_version: Rc<Cell<u64>>,
+    query_history_menu: gio::Menu,
+    replace_history_menu: gio::Menu,
     // /// Settings for the search
     // search_settings: sourceview5::SearchSettings,
 }
 
+/// Matches found inside the search region, see
+/// [`SearchBar::region_match_offsets`]
+#[derive(Debug)]
+struct RegionMatches {
+    query: SearchQuery,
+    /// Start and end offset of the region
+    region: (i32, i32),
+    buffer_version: u64,
+    offsets: Vec<(i32, i32)>,
+}
+
 #[derive(Debug, Clone)]
 pub enum SearchBarMsg {
     Trigger,
@@ -21,19 +82,514 @@ pub enum SearchBarMsg {
     SetSearchRegex(bool),
     SetSearchCaseSensitive(bool),
     SetWordBoundarySearch(bool),
+    SetInSelection(bool),
 
     ReplaceInBuffer,
     ReplaceAllInBuffer,
 
     IterateNextMatch,
     IteratePreviousMatch,
+
+    /// Recalculate the match counter and regex error
+    RefreshMatchInfo,
+    /// The cursor or selection moved, find which match is selected now
+    RefreshMatchPosition,
+
+    /// Go to an older query in the history (Up)
+    HistoryOlder,
+    /// Go to a newer query in the history (Down)
+    HistoryNewer,
+    /// Restore a query and its options from the history
+    UseQueryFromHistory(usize),
+    /// Restore a replacement from the history
+    UseReplacementFromHistory(usize),
+
+    /// Search for the current query in every file of a folder
+    FindInFiles,
+    /// Preview replacing the current query in every file of a folder
+    ReplaceInFiles,
+
+    /// Turn replacing in the buffer off or back on, for while the buffer
+    /// can't be edited
+    SetReadOnly(bool),
+}
+
+#[derive(Debug)]
+pub enum SearchBarOutput {
+    /// Search for a query across files
+    FindInFiles(SearchQuery),
+    /// Replace a query across files, with the given replacement
+    ReplaceInFiles(SearchQuery, String),
+}
+
+impl SearchBar {
+    fn buffer(&self) -> sourceview5::Buffer {
+        self.search_context.buffer()
+    }
+
+    /// Select a match in the buffer and scroll the view to it
+    fn select_match(&self, start: &gtk::TextIter, end: &gtk::TextIter) {
+        let buffer = self.buffer();
+        buffer.select_range(start, end);
+        self.source_view
+            .scroll_to_mark(&buffer.get_insert(), 0.25, false, 0.0, 0.0);
+    }
+
+    /// Iterators at a pair of character offsets
+    fn iters_at(&self, start: i32, end: i32) -> (gtk::TextIter, gtk::TextIter) {
+        let buffer = self.buffer();
+        (buffer.iter_at_offset(start), buffer.iter_at_offset(end))
+    }
+
+    /// Bounds of the current selection, or the cursor position if nothing is selected
+    fn selection_or_cursor(&self) -> (gtk::TextIter, gtk::TextIter) {
+        let buffer = self.buffer();
+        buffer.selection_bounds().unwrap_or_else(|| {
+            let cursor = buffer.iter_at_mark(&buffer.get_insert());
+            (cursor, cursor)
+        })
+    }
+
+    /// Returns the current selection if it is exactly one of the search matches
+    fn selected_match(&self) -> Option<(gtk::TextIter, gtk::TextIter)> {
+        let (start, end) = self.buffer().selection_bounds()?;
+        if let Some((region_start, region_end)) = self.search_region() {
+            if start < region_start || end > region_end {
+                return None;
+            }
+        }
+        (self.search_context.occurrence_position(&start, &end) > 0).then_some((start, end))
+    }
+
+    /// The current query and its options, if there's one
+    fn current_query(&self) -> Option<SearchQuery> {
+        let settings = self.search_context.settings();
+        Some(SearchQuery {
+            text: settings.search_text()?.into(),
+            regex: settings.is_regex_enabled(),
+            case_sensitive: settings.is_case_sensitive(),
+            whole_words: settings.is_at_word_boundaries(),
+        })
+    }
+
+    /// Add the current query and its options to the history
+    fn record_query(&mut self) {
+        let Some(query) = self.current_query() else {
+            return;
+        };
+        search_history::with_shared(|history| history.add_query(query));
+        self.history_index = None;
+        self.refresh_history_menus();
+    }
+
+    fn record_replacement(&mut self) {
+        let replacement = self.replace_entry.text();
+        search_history::with_shared(|history| history.add_replacement(&replacement));
+        self.refresh_history_menus();
+    }
+
+    /// Rebuild the history dropdowns, in every window
+    fn refresh_history_menus(&self) {
+        search_history::with_shared(|history| {
+            self.fill_history_menus(&history.queries, &history.replacements)
+        });
+    }
+
+    /// List `queries` and `replacements` in the history dropdowns
+    fn fill_history_menus(&self, queries: &[SearchQuery], replacements: &[String]) {
+        // Menu labels use `_` for mnemonics
+        let label = |text: &str| text.replace('_', "__");
+
+        self.query_history_menu.remove_all();
+        for (i, query) in queries.iter().enumerate() {
+            let options: Vec<&str> = [
+                (query.regex, "regex"),
+                (query.case_sensitive, "case sensitive"),
+                (query.whole_words, "whole words"),
+            ]
+            .into_iter()
+            .filter_map(|(enabled, name)| enabled.then_some(name))
+            .collect();
+            let text = if options.is_empty() {
+                label(&query.text)
+            } else {
+                format!("{} ({})", label(&query.text), options.join(", "))
+            };
+            let item = gio::MenuItem::new(Some(&text), None);
+            item.set_action_and_target_value(
+                Some("search-history.query"),
+                Some(&(i as i32).to_variant()),
+            );
+            self.query_history_menu.append_item(&item);
+        }
+
+        self.replace_history_menu.remove_all();
+        for (i, replacement) in replacements.iter().enumerate() {
+            let item = gio::MenuItem::new(Some(&label(replacement)), None);
+            item.set_action_and_target_value(
+                Some("search-history.replacement"),
+                Some(&(i as i32).to_variant()),
+            );
+            self.replace_history_menu.append_item(&item);
+        }
+    }
+
+    /// Put a query from the history back into the search bar, options included
+    fn use_query_from_history(&mut self, index: usize) {
+        let Some(query) =
+            search_history::with_shared(|history| history.queries.get(index).cloned())
+        else {
+            return;
+        };
+        let settings = self.search_context.settings();
+        settings.set_regex_enabled(query.regex);
+        settings.set_case_sensitive(query.case_sensitive);
+        settings.set_at_word_boundaries(query.whole_words);
+        self.history_index = Some(index);
+        // Updates the search text through `connect_search_changed`
+        self.search_entry.set_text(&query.text);
+        self.search_entry.set_position(-1);
+    }
+
+    /// Browse the query history, `older` being Up and `!older` being Down
+    fn step_history(&mut self, older: bool) {
+        let len = search_history::with_shared(|history| history.queries.len());
+        let index = match (self.history_index, older) {
+            (None, true) if len > 0 => 0,
+            (Some(i), true) => (i + 1).min(len - 1),
+            (Some(i), false) if i > 0 => i - 1,
+            // Went past the newest entry, back to an empty query
+            (Some(_), false) => {
+                self.history_index = None;
+                self.search_entry.set_text("");
+                return;
+            }
+            _ => return,
+        };
+        self.use_query_from_history(index);
+    }
+
+    /// Remember the current selection as the range for "In selection", or
+    /// forget the previous one if nothing is selected
+    fn capture_selection(&mut self) {
+        let buffer = self.buffer();
+        if let Some((start, end)) = self.selection_marks.take() {
+            buffer.delete_mark(&start);
+            buffer.delete_mark(&end);
+        }
+        self.selection_marks = buffer.selection_bounds().map(|(start, end)| {
+            (
+                buffer.create_mark(None, &start, true),
+                buffer.create_mark(None, &end, false),
+            )
+        });
+    }
+
+    /// The range searching is restricted to, if "In selection" is enabled
+    fn search_region(&self) -> Option<(gtk::TextIter, gtk::TextIter)> {
+        if !self.in_selection {
+            return None;
+        }
+        let (start, end) = self.selection_marks.as_ref()?;
+        let buffer = self.buffer();
+        Some((buffer.iter_at_mark(start), buffer.iter_at_mark(end)))
+    }
+
+    /// The current query compiled the way `SearchContext` matches it, reused
+    /// while the query stays the same
+    ///
+    /// Only used on matches the context found, so it leaves checking word
+    /// boundaries to the context.
+    fn query_regex(&mut self) -> Result<glib::Regex, String> {
+        let query = self
+            .current_query()
+            .map(|query| SearchQuery {
+                whole_words: false,
+                ..query
+            })
+            .ok_or_else(|| "Nothing to search for".to_string())?;
+        if let Some((cached, regex)) = &self.regex_cache {
+            if *cached == query {
+                return Ok(regex.clone());
+            }
+        }
+        let regex = project_search::build_regex(&query)?;
+        self.regex_cache = Some((query, regex.clone()));
+        Ok(regex)
+    }
+
+    /// Offsets of every match inside the region, if they're already known
+    /// for the current query, region and buffer
+    fn cached_region_offsets(
+        &self,
+        region_start: &gtk::TextIter,
+        region_end: &gtk::TextIter,
+    ) -> Option<&[(i32, i32)]> {
+        let query = self.current_query()?;
+        let region = (region_start.offset(), region_end.offset());
+        let buffer_version = self.buffer_version.get();
+        self.region_cache
+            .as_ref()
+            .filter(|cache| {
+                cache.query == query
+                    && cache.region == region
+                    && cache.buffer_version == buffer_version
+            })
+            .map(|cache| cache.offsets.as_slice())
+    }
+
+    /// Offsets of every match inside the region, in buffer order
+    ///
+    /// Kept until the query, the region or the buffer changes, so moving the
+    /// cursor doesn't search again.
+    fn region_match_offsets(
+        &mut self,
+        region_start: &gtk::TextIter,
+        region_end: &gtk::TextIter,
+    ) -> &[(i32, i32)] {
+        let Some(query) = self.current_query() else {
+            return &[];
+        };
+        if self
+            .cached_region_offsets(region_start, region_end)
+            .is_none()
+        {
+            let region = (region_start.offset(), region_end.offset());
+            let buffer_version = self.buffer_version.get();
+            let offsets = self.find_in_region(region_start, region_end);
+            self.region_cache = Some(RegionMatches {
+                query,
+                region,
+                buffer_version,
+                offsets,
+            });
+        }
+        self.region_cache
+            .as_ref()
+            .map_or(&[][..], |cache| cache.offsets.as_slice())
+    }
+
+    /// The search context's matches inside the region
+    ///
+    /// Once the context has scanned the buffer, `forward` only follows the
+    /// matches it highlighted instead of searching the text again. Going by
+    /// them also matches exactly like the context does, word boundaries
+    /// included.
+    fn find_in_region(
+        &self,
+        region_start: &gtk::TextIter,
+        region_end: &gtk::TextIter,
+    ) -> Vec<(i32, i32)> {
+        let mut offsets = Vec::new();
+        let mut from = *region_start;
+        while let Some((start, end, wrapped)) = self.search_context.forward(&from) {
+            if wrapped || start < from || end > *region_end {
+                break;
+            }
+            offsets.push((start.offset(), end.offset()));
+            from = end;
+            // An empty match would be found again at the same place
+            if start == end && !from.forward_char() {
+                break;
+            }
+        }
+        offsets
+    }
+
+    /// Re-read the occurrence count, selected match position and regex error
+    /// from the search context
+    fn refresh_match_info(&mut self) {
+        self.regex_error = self
+            .search_context
+            .regex_error()
+            .map(|e| e.message().to_string());
+        self.match_count = self.search_context.occurrences_count();
+        // The region is only searched once the context is done scanning the
+        // buffer, which notifies again then. Nobody sees the count while
+        // the bar is hidden.
+        if self.match_count != -1 && self.find_revealer.reveals_child() {
+            if let Some((region_start, region_end)) = self.search_region() {
+                self.match_count =
+                    self.region_match_offsets(&region_start, &region_end).len() as i32;
+            }
+        }
+
+        if self.regex_error.is_some() {
+            self.search_entry.add_css_class("error");
+        } else {
+            self.search_entry.remove_css_class("error");
+        }
+
+        self.refresh_match_position();
+    }
+
+    /// Find which match is selected, without searching again
+    fn refresh_match_position(&mut self) {
+        let selection = self.buffer().selection_bounds();
+        self.match_position = match self.search_region() {
+            Some((region_start, region_end)) => selection
+                .zip(self.cached_region_offsets(&region_start, &region_end))
+                .and_then(|((start, end), offsets)| {
+                    offsets.binary_search(&(start.offset(), end.offset())).ok()
+                })
+                .map(|i| i as i32 + 1)
+                .unwrap_or(0),
+            None => selection
+                .map(|(start, end)| self.search_context.occurrence_position(&start, &end))
+                .unwrap_or(0),
+        };
+        self.refresh_replacement_preview();
+    }
+
+    /// Update the preview of the replacement for the selected match
+    fn refresh_replacement_preview(&mut self) {
+        let replacement = self.replace_entry.text();
+        let preview = match self.selected_match() {
+            Some((start, end)) => {
+                let original = self.buffer().text(&start, &end, true);
+                Some(
+                    self.expand_replacement(&start, &end, &replacement)
+                        .map(|expanded| format!("{} → {}", original, expanded)),
+                )
+            }
+            None => None,
+        };
+
+        match preview {
+            Some(Err(e)) => {
+                self.replace_entry.add_css_class("error");
+                self.replacement_preview = e;
+            }
+            Some(Ok(preview)) => {
+                self.replace_entry.remove_css_class("error");
+                self.replacement_preview = preview;
+            }
+            None => {
+                self.replace_entry.remove_css_class("error");
+                self.replacement_preview.clear();
+            }
+        }
+    }
+
+    /// Expand `replacement` for the match between `start` and `end`, the same
+    /// way `SearchContext::replace` does.
+    ///
+    /// With regex enabled, the replacement may contain `\0`-`\9` and `\g<name>`
+    /// backreferences, and the `\U`, `\L`, `\u`, `\l` and `\E` case modifiers.
+    fn expand_replacement(
+        &mut self,
+        start: &gtk::TextIter,
+        end: &gtk::TextIter,
+        replacement: &str,
+    ) -> Result<String, String> {
+        if !self.search_context.settings().is_regex_enabled() {
+            return Ok(replacement.to_string());
+        }
+
+        glib::Regex::check_replacement(replacement).map_err(|e| e.message().to_string())?;
+        let regex = self.query_regex()?;
+
+        // Match against the surrounding lines, so anchors and lookarounds
+        // behave like they do in the buffer
+        let buffer = self.buffer();
+        let mut context_start = *start;
+        context_start.set_line_offset(0);
+        let mut context_end = *end;
+        if !context_end.ends_line() {
+            context_end.forward_to_line_end();
+        }
+        let before = buffer.text(&context_start, start, true);
+        let context = buffer.text(&context_start, &context_end, true);
+
+        let info = regex
+            .match_full(
+                context.as_gstr(),
+                before.len() as i32,
+                glib::RegexMatchFlags::ANCHORED,
+            )
+            .map_err(|e| e.message().to_string())?;
+        if !info.matches() {
+            return Err("Selection doesn't match the pattern".to_string());
+        }
+
+        info.expand_references(replacement)
+            .map(|expanded| expanded.map(String::from).unwrap_or_default())
+            .map_err(|e| e.message().to_string())
+    }
+
+    /// Text for the match counter, e.g. "3 of 12"
+    fn match_info_text(&self) -> String {
+        if self.search_context.settings().search_text().is_none() {
+            return String::new();
+        }
+        if self.regex_error.is_some() {
+            return "Invalid pattern".to_string();
+        }
+        match (self.match_position, self.match_count) {
+            (_, -1) => "…".to_string(),
+            (_, 0) => "No matches".to_string(),
+            (1.., count) => format!("{} of {}", self.match_position, count),
+            (_, 1) => "1 match".to_string(),
+            (_, count) => format!("{} matches", count),
+        }
+    }
+
+    fn next_match(&mut self) {
+        let (_, from) = self.selection_or_cursor();
+        let found = match self.search_region() {
+            None => self
+                .search_context
+                .forward(&from)
+                .map(|(start, end, _wrapped)| (start, end)),
+            Some((region_start, region_end)) => {
+                let from = from.clamp(region_start, region_end).offset();
+                let offsets = self.region_match_offsets(&region_start, &region_end);
+                // Wrap around to the start of the region
+                let found = offsets
+                    .iter()
+                    .find(|&&(start, _)| start >= from)
+                    .or(offsets.first())
+                    .copied();
+                found.map(|(start, end)| self.iters_at(start, end))
+            }
+        };
+        if let Some((start, end)) = found {
+            self.select_match(&start, &end);
+        }
+    }
+
+    fn previous_match(&mut self) {
+        let (from, _) = self.selection_or_cursor();
+        let found = match self.search_region() {
+            None => self
+                .search_context
+                .backward(&from)
+                .map(|(start, end, _wrapped)| (start, end)),
+            Some((region_start, region_end)) => {
+                let from = from.clamp(region_start, region_end).offset();
+                let offsets = self.region_match_offsets(&region_start, &region_end);
+                // Wrap around to the end of the region
+                let found = offsets
+                    .iter()
+                    .rev()
+                    .find(|&&(_, end)| end <= from)
+                    .or(offsets.last())
+                    .copied();
+                found.map(|(start, end)| self.iters_at(start, end))
+            }
+        };
+        if let Some((start, end)) = found {
+            self.select_match(&start, &end);
+        }
+    }
 }
 
 #[relm4::component(pub)]
 impl relm4::SimpleComponent for SearchBar {
-    type Init = sourceview5::Buffer;
+    /// The view to search in, with its `sourceview5::Buffer` already set
+    type Init = sourceview5::View;
     type Input = SearchBarMsg;
-    type Output = ();
+    type Output = SearchBarOutput;
 
     view! {
         gtk::Box {
@@ -53,45 +609,193 @@ impl relm4::SimpleComponent for SearchBar {
                     inline_css: "background-color: @surface_bright_bg_color; border-radius: 8px; padding: 8px; drop-shadow: 0 0 8px rgba(0, 0, 0, 0.5);",
                     set_margin_all: 16,
                     // set_row_homogeneous: true,
+                    set_row_spacing: 4,
+                    set_column_spacing: 4,
                     set_orientation: gtk::Orientation::Vertical,
 
                     // column, row, width, height
 
                     #[local_ref]
-                    attach[0, 0, 1, 1] = search_entry -> gtk::SearchEntry {
+                    attach[0, 0, 2, 1] = search_entry -> gtk::SearchEntry {
                         set_placeholder_text: Some("Search"),
+                        // Show what's wrong with an invalid regex
+                        #[watch]
+                        set_tooltip_text: model.regex_error.as_deref(),
                         connect_search_changed[sender] => move |search_entry| {
                             let query = search_entry.text();
-                            
+
                             sender.input(SearchBarMsg::UpdateSearchQuery(query.into()));
                         },
+                        // Enter, and Ctrl+G / Ctrl+Shift+G
+                        connect_activate[sender] => move |_| {
+                            sender.input(SearchBarMsg::IterateNextMatch);
+                        },
+                        connect_next_match[sender] => move |_| {
+                            sender.input(SearchBarMsg::IterateNextMatch);
+                        },
+                        connect_previous_match[sender] => move |_| {
+                            sender.input(SearchBarMsg::IteratePreviousMatch);
+                        },
+                        connect_stop_search[sender] => move |_| {
+                            sender.input(SearchBarMsg::Trigger);
+                        },
+                        // Shift+Enter goes to the previous match
+                        add_controller = gtk::EventControllerKey {
+                            set_propagation_phase: gtk::PropagationPhase::Capture,
+                            connect_key_pressed[sender] => move |_, key, _, modifiers| {
+                                match key {
+                                    gtk::gdk::Key::Return | gtk::gdk::Key::KP_Enter
+                                        if modifiers.contains(gtk::gdk::ModifierType::SHIFT_MASK) =>
+                                    {
+                                        sender.input(SearchBarMsg::IteratePreviousMatch);
+                                    }
+                                    // Browse the search history
+                                    gtk::gdk::Key::Up | gtk::gdk::Key::KP_Up => {
+                                        sender.input(SearchBarMsg::HistoryOlder);
+                                    }
+                                    gtk::gdk::Key::Down | gtk::gdk::Key::KP_Down => {
+                                        sender.input(SearchBarMsg::HistoryNewer);
+                                    }
+                                    _ => return gtk::glib::Propagation::Proceed,
+                                }
+                                gtk::glib::Propagation::Stop
+                            },
+                        },
+                    },
+
+                    attach[2, 0, 1, 1] = &gtk::Label {
+                        add_css_class: "dim-label",
+                        set_width_chars: 10,
+                        #[watch]
+                        set_label: &model.match_info_text(),
+                    },
+
+                    attach[3, 0, 1, 1] = &gtk::Button {
+                        set_icon_name: "go-up-symbolic",
+                        set_tooltip_text: Some("Previous match"),
+                        connect_clicked[sender] => move |_| {
+                            sender.input(SearchBarMsg::IteratePreviousMatch);
+                        },
+                    },
+
+                    attach[4, 0, 1, 1] = &gtk::Button {
+                        set_icon_name: "go-down-symbolic",
+                        set_tooltip_text: Some("Next match"),
+                        connect_clicked[sender] => move |_| {
+                            sender.input(SearchBarMsg::IterateNextMatch);
+                        },
+                    },
+
+                    #[local_ref]
+                    attach[0, 1, 3, 1] = replace_entry -> gtk::Entry {
+                        set_placeholder_text: Some("Replace"),
+                        #[watch]
+                        set_sensitive: !model.read_only,
+                        set_tooltip_text: Some("With Regex enabled, use \\1 or \\g<name> for groups and \\U, \\L, \\u, \\l, \\E to change case"),
+                        connect_changed[sender] => move |_| {
+                            sender.input(SearchBarMsg::RefreshMatchInfo);
+                        },
+                        connect_activate[sender] => move |_| {
+                            sender.input(SearchBarMsg::ReplaceInBuffer);
+                        },
+                    },
+
+                    attach[5, 0, 1, 1] = &gtk::MenuButton {
+                        set_icon_name: "document-open-recent-symbolic",
+                        set_tooltip_text: Some("Recent searches"),
+                        set_menu_model: Some(&model.query_history_menu),
+                    },
+
+                    attach[5, 1, 1, 1] = &gtk::MenuButton {
+                        set_icon_name: "document-open-recent-symbolic",
+                        set_tooltip_text: Some("Recent replacements"),
+                        set_menu_model: Some(&model.replace_history_menu),
+                    },
+
+                    attach[3, 1, 1, 1] = &gtk::Button {
+                        set_label: "Replace",
+                        #[watch]
+                        set_sensitive: !model.read_only,
+                        connect_clicked[sender] => move |_| {
+                            sender.input(SearchBarMsg::ReplaceInBuffer);
+                        },
+                    },
+
+                    attach[4, 1, 1, 1] = &gtk::Button {
+                        set_label: "Replace All",
+                        #[watch]
+                        set_sensitive: !model.read_only,
+                        connect_clicked[sender] => move |_| {
+                            sender.input(SearchBarMsg::ReplaceAllInBuffer);
+                        },
                     },
-                    
-                    
-                    
-                    attach[0, 1, 1, 1] = &gtk::CheckButton {
+
+                    attach[0, 3, 7, 1] = &gtk::Label {
+                        add_css_class: "dim-label",
+                        set_xalign: 0.0,
+                        set_ellipsize: gtk::pango::EllipsizeMode::End,
+                        #[watch]
+                        set_visible: !model.replacement_preview.is_empty(),
+                        #[watch]
+                        set_label: &model.replacement_preview,
+                    },
+
+                    attach[0, 2, 1, 1] = &gtk::CheckButton {
                         set_label: Some("Regex"),
+                        #[watch]
+                        set_active: model.search_context.settings().is_regex_enabled(),
                         connect_toggled[sender] => move |button| {
                             let active = button.is_active();
                             sender.input(SearchBarMsg::SetSearchRegex(active));
                         },
                     },
-                    
-                    attach[1, 1, 1, 1] = &gtk::CheckButton {
+
+                    attach[1, 2, 1, 1] = &gtk::CheckButton {
                         set_label: Some("Case Sensitive"),
+                        #[watch]
+                        set_active: model.search_context.settings().is_case_sensitive(),
                         connect_toggled[sender] => move |button| {
                             let active = button.is_active();
                             sender.input(SearchBarMsg::SetSearchCaseSensitive(active));
                         },
                     },
-                    
-                    attach[2, 1, 1, 1] = &gtk::CheckButton {
+
+                    attach[2, 2, 1, 1] = &gtk::CheckButton {
                         set_label: Some("Whole Words"),
+                        #[watch]
+                        set_active: model.search_context.settings().is_at_word_boundaries(),
                         connect_toggled[sender] => move |button| {
                             let active = button.is_active();
                             sender.input(SearchBarMsg::SetWordBoundarySearch(active));
                         },
                     },
+
+                    attach[6, 0, 1, 1] = &gtk::Button {
+                        set_icon_name: "folder-saved-search-symbolic",
+                        set_tooltip_text: Some("Find in files..."),
+                        connect_clicked[sender] => move |_| {
+                            sender.input(SearchBarMsg::FindInFiles);
+                        },
+                    },
+
+                    attach[6, 1, 1, 1] = &gtk::Button {
+                        set_icon_name: "edit-find-replace-symbolic",
+                        set_tooltip_text: Some("Replace in files..."),
+                        connect_clicked[sender] => move |_| {
+                            sender.input(SearchBarMsg::ReplaceInFiles);
+                        },
+                    },
+
+                    attach[3, 2, 2, 1] = &gtk::CheckButton {
+                        set_label: Some("In selection"),
+                        set_tooltip_text: Some("Only search the text that was selected when the search bar was opened"),
+                        #[watch]
+                        set_sensitive: model.selection_marks.is_some(),
+                        connect_toggled[sender] => move |button| {
+                            let active = button.is_active();
+                            sender.input(SearchBarMsg::SetInSelection(active));
+                        },
+                    },
                 },
                 // layout
             },
@@ -103,17 +807,82 @@ impl relm4::SimpleComponent for SearchBar {
         root: Self::Root,
         sender: relm4::ComponentSender<Self>,
     ) -> relm4::ComponentParts<Self> {
+        let buffer = init
+            .buffer()
+            .downcast::<sourceview5::Buffer>()
+            .expect("source view should hold a sourceview5::Buffer");
+
+        let search_settings = sourceview5::SearchSettings::new();
+        // Keep iterating past the end/start of the buffer
+        search_settings.set_wrap_around(true);
+
+        let (query_history_menu, replace_history_menu) = HISTORY_MENUS.with(Clone::clone);
         let model = Self {
-            search_context: sourceview5::SearchContext::new(
-                &init,
-                Some(&sourceview5::SearchSettings::new()),
-            ),
+            search_context: sourceview5::SearchContext::new(&buffer, Some(&search_settings)),
+            source_view: init,
+            query_history_menu,
+            replace_history_menu,
             // search_settings,
             ..Default::default()
         };
+        model.refresh_history_menus();
+
+        let history_actions = gio::SimpleActionGroup::new();
+        {
+            let sender = sender.clone();
+            let action = gio::SimpleAction::new("query", Some(glib::VariantTy::INT32));
+            action.connect_activate(move |_, index| {
+                if let Some(index) = index.and_then(|i| i.get::<i32>()) {
+                    sender.input(SearchBarMsg::UseQueryFromHistory(index as usize));
+                }
+            });
+            history_actions.add_action(&action);
+        }
+        {
+            let sender = sender.clone();
+            let action = gio::SimpleAction::new("replacement", Some(glib::VariantTy::INT32));
+            action.connect_activate(move |_, index| {
+                if let Some(index) = index.and_then(|i| i.get::<i32>()) {
+                    sender.input(SearchBarMsg::UseReplacementFromHistory(index as usize));
+                }
+            });
+            history_actions.add_action(&action);
+        }
+        root.insert_action_group("search-history", Some(&history_actions));
+
+        {
+            let sender = sender.clone();
+            model
+                .search_context
+                .connect_occurrences_count_notify(move |_| {
+                    sender.input(SearchBarMsg::RefreshMatchInfo);
+                });
+        }
+        {
+            let sender = sender.clone();
+            model.search_context.connect_regex_error_notify(move |_| {
+                sender.input(SearchBarMsg::RefreshMatchInfo);
+            });
+        }
+        {
+            let buffer_version = model.buffer_version.clone();
+            buffer.connect_changed(move |_| {
+                buffer_version.set(buffer_version.get() + 1);
+            });
+        }
+        {
+            // Keep "N of M" in sync with the selected match
+            let sender = sender.clone();
+            buffer.connect_mark_set(move |buffer, _, mark| {
+                if mark == &buffer.get_insert() || mark == &buffer.selection_bound() {
+                    sender.input(SearchBarMsg::RefreshMatchPosition);
+                }
+            });
+        }
 
         let find_revealer = &model.find_revealer;
         let search_entry = &model.search_entry;
+        let replace_entry = &model.replace_entry;
         let widgets = view_output!();
         relm4::ComponentParts { model, widgets }
     }
@@ -121,42 +890,147 @@ impl relm4::SimpleComponent for SearchBar {
     fn update(&mut self, msg: Self::Input, sender: relm4::ComponentSender<Self>) {
         match msg {
             SearchBarMsg::Trigger => {
-                self.find_revealer
-                    .set_reveal_child(!self.find_revealer.is_child_revealed());
-                self.find_revealer.activate();
+                let reveal = !self.find_revealer.reveals_child();
+                self.find_revealer.set_reveal_child(reveal);
+                if reveal {
+                    self.capture_selection();
+                    self.refresh_match_info();
+                    self.search_entry.grab_focus();
+                } else {
+                    self.record_query();
+                    self.source_view.grab_focus();
+                }
             }
             SearchBarMsg::UpdateSearchQuery(query) => {
                 self.search_context
                     .settings()
                     .set_search_text((!query.is_empty()).then_some(&query));
-                
+
                 let txt = self.search_context.settings().search_text();
-                
+
                 println!("Search query: {:?}", txt);
+                self.refresh_match_info();
             }
             SearchBarMsg::SetSearchRegex(opt) => {
                 // Set the search to use regex
                 self.search_context.settings().set_regex_enabled(opt);
+                self.refresh_match_info();
             }
             SearchBarMsg::SetSearchCaseSensitive(opt) => {
                 // Set the search to be case sensitive
                 self.search_context.settings().set_case_sensitive(opt);
+                self.refresh_match_info();
             }
             SearchBarMsg::SetWordBoundarySearch(opt) => {
                 // Set the search to match whole words
                 self.search_context.settings().set_at_word_boundaries(opt);
+                self.refresh_match_info();
+            }
+            SearchBarMsg::SetInSelection(opt) => {
+                self.in_selection = opt;
+                self.refresh_match_info();
             }
+            SearchBarMsg::ReplaceInBuffer if self.read_only => {}
+            SearchBarMsg::ReplaceAllInBuffer if self.read_only => {}
             SearchBarMsg::ReplaceInBuffer => {
-                // Replace the current match in the buffer
-                // self.search_context.replace
-                todo!()
+                // Replace the current match in the buffer, if the selection is one.
+                // Otherwise just move to the next match so the user can see what
+                // will be replaced.
+                let Some((mut start, mut end)) = self.selected_match() else {
+                    self.next_match();
+                    return;
+                };
+                let replacement = self.replace_entry.text();
+                if let Err(e) = self.expand_replacement(&start, &end, &replacement) {
+                    println!("Invalid replacement: {}", e);
+                    return;
+                }
+                if let Err(e) = self
+                    .search_context
+                    .replace(&mut start, &mut end, &replacement)
+                {
+                    println!("Error replacing match: {}", e);
+                    return;
+                }
+                self.record_query();
+                self.record_replacement();
+                // `end` now points past the replaced text
+                self.buffer().place_cursor(&end);
+                self.next_match();
+            }
+            SearchBarMsg::ReplaceAllInBuffer => {
+                // `replace_all` wraps every replacement in a single user action,
+                // so it can be undone in one step
+                let replacement = self.replace_entry.text();
+                if self.search_context.settings().is_regex_enabled() {
+                    if let Err(e) = glib::Regex::check_replacement(replacement.as_str()) {
+                        println!("Invalid replacement: {}", e);
+                        return;
+                    }
+                }
+                if let Some((region_start, region_end)) = self.search_region() {
+                    // Replace back to front, so the offsets of the remaining
+                    // matches stay valid
+                    let buffer = self.buffer();
+                    let offsets = self
+                        .region_match_offsets(&region_start, &region_end)
+                        .to_vec();
+                    buffer.begin_user_action();
+                    for (start, end) in offsets.into_iter().rev() {
+                        let mut start = buffer.iter_at_offset(start);
+                        let mut end = buffer.iter_at_offset(end);
+                        if let Err(e) =
+                            self.search_context
+                                .replace(&mut start, &mut end, &replacement)
+                        {
+                            println!("Error replacing match: {}", e);
+                            break;
+                        }
+                    }
+                    buffer.end_user_action();
+                } else if let Err(e) = self.search_context.replace_all(&replacement) {
+                    println!("Error replacing matches: {}", e);
+                    return;
+                }
+                self.record_query();
+                self.record_replacement();
             }
-            SearchBarMsg::ReplaceAllInBuffer => todo!(),
             SearchBarMsg::IterateNextMatch => {
-                todo!()
-                // self.search_context.forward(&something)
+                self.record_query();
+                self.next_match();
+            }
+            SearchBarMsg::IteratePreviousMatch => {
+                self.record_query();
+                self.previous_match();
+            }
+            SearchBarMsg::RefreshMatchInfo => self.refresh_match_info(),
+            SearchBarMsg::RefreshMatchPosition => self.refresh_match_position(),
+            SearchBarMsg::HistoryOlder => self.step_history(true),
+            SearchBarMsg::HistoryNewer => self.step_history(false),
+            SearchBarMsg::UseQueryFromHistory(index) => self.use_query_from_history(index),
+            SearchBarMsg::FindInFiles => {
+                if let Some(query) = self.current_query() {
+                    self.record_query();
+                    let _ = sender.output(SearchBarOutput::FindInFiles(query));
+                }
+            }
+            SearchBarMsg::ReplaceInFiles => {
+                if let Some(query) = self.current_query() {
+                    self.record_query();
+                    self.record_replacement();
+                    let replacement = self.replace_entry.text().to_string();
+                    let _ = sender.output(SearchBarOutput::ReplaceInFiles(query, replacement));
+                }
+            }
+            SearchBarMsg::SetReadOnly(read_only) => self.read_only = read_only,
+            SearchBarMsg::UseReplacementFromHistory(index) => {
+                let replacement =
+                    search_history::with_shared(|history| history.replacements.get(index).cloned());
+                if let Some(replacement) = replacement {
+                    self.replace_entry.set_text(&replacement);
+                    self.replace_entry.set_position(-1);
+                }
             }
-            SearchBarMsg::IteratePreviousMatch => todo!(),
         }
     }
 }
@@ -0,0 +1,164 @@
+use std::cell::{Cell, RefCell};
+
+use gtk4::prelude::*;
+use gtk4::{gio, glib};
+use relm4::prelude::*;
+use relm4::{main_application, Controller, Sender};
+
+use crate::cli::OpenRequest;
+use crate::file_format::DecodedText;
+use crate::{AppMsg, MainWindow};
+
+/// An open document window
+struct DocumentWindow {
+    window: libhelium::ApplicationWindow,
+    sender: Sender<AppMsg>,
+    /// Keeps the window's component running until the window is destroyed.
+    /// `None` for the window opened at startup, which `RelmApp` keeps.
+    controller: Option<Controller<MainWindow>>,
+    /// `--wait` invocations blocked on this window, they return once dropped
+    waiters: Vec<gio::ApplicationCommandLine>,
+}
+
+thread_local! {
+    /// Every open document window, in the order they were opened
+    static WINDOWS: RefCell<Vec<DocumentWindow>> = const { RefCell::new(Vec::new()) };
+    /// Set while quitting, each window closing moves on to the next one
+    static QUITTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Keep track of a document window, until it's destroyed
+pub(crate) fn register(window: &libhelium::ApplicationWindow, sender: Sender<AppMsg>) {
+    WINDOWS.with_borrow_mut(|windows| {
+        windows.push(DocumentWindow {
+            window: window.clone(),
+            sender,
+            controller: None,
+            waiters: Vec::new(),
+        })
+    });
+    window.connect_destroy(|window| {
+        // Dropping the waiters lets their invocations exit
+        let closed = WINDOWS.with_borrow_mut(|windows| {
+            let i = windows.iter().position(|w| &w.window == window)?;
+            Some(windows.remove(i))
+        });
+        // The window may be destroyed from its own component's update, so
+        // the component is only shut down once that returned
+        if let Some(controller) = closed.and_then(|w| w.controller) {
+            glib::idle_add_local_once(move || drop(controller));
+        }
+        if QUITTING.get() {
+            close_next_window();
+        }
+    });
+}
+
+/// Ask the first remaining window to close
+fn close_next_window() {
+    let first = WINDOWS.with_borrow(|windows| windows.first().map(|w| w.sender.clone()));
+    if let Some(sender) = first {
+        sender.emit(AppMsg::Close);
+    }
+}
+
+/// Close every window one after the other, so each one with unsaved changes
+/// gets to ask about them
+///
+/// The application exits once the last window is gone.
+pub(crate) fn quit() {
+    QUITTING.set(true);
+    close_next_window();
+}
+
+/// Stop quitting, because a window was kept open
+pub(crate) fn cancel_quit() {
+    QUITTING.set(false);
+}
+
+/// Keep a `--wait` invocation running until `window` is closed
+pub(crate) fn hold_until_closed(
+    window: &libhelium::ApplicationWindow,
+    waiter: gio::ApplicationCommandLine,
+) {
+    WINDOWS.with_borrow_mut(|windows| {
+        if let Some(w) = windows.iter_mut().find(|w| &w.window == window) {
+            w.waiters.push(waiter);
+        }
+    });
+}
+
+/// Let every `--wait` invocation return, before the application exits
+pub(crate) fn release_waiters() {
+    let waiters: Vec<gio::ApplicationCommandLine> = WINDOWS.with_borrow_mut(|windows| {
+        windows
+            .iter_mut()
+            .flat_map(|w| std::mem::take(&mut w.waiters))
+            .collect()
+    });
+    drop(waiters);
+    // Remote invocations are only told they're done over D-Bus
+    if let Some(connection) = main_application().dbus_connection() {
+        if let Err(e) = connection.flush_sync(None::<&gio::Cancellable>) {
+            println!("Error flushing D-Bus connection: {}", e);
+        }
+    }
+}
+
+/// Open a new document window, optionally loading a file in it
+pub(crate) fn open_new_window(request: Option<OpenRequest>) -> Sender<AppMsg> {
+    let controller = MainWindow::builder().launch(request).detach();
+    let window = controller.widget().clone();
+    let sender = controller.sender().clone();
+    // The window registered itself while it was set up
+    WINDOWS.with_borrow_mut(|windows| {
+        if let Some(w) = windows.iter_mut().find(|w| w.window == window) {
+            w.controller = Some(controller);
+        }
+    });
+    main_application().add_window(&window);
+    window.present();
+    sender
+}
+
+/// The sender of the active window, or the last one opened
+fn target_window() -> Option<Sender<AppMsg>> {
+    let active = main_application().active_window();
+    WINDOWS.with_borrow(|windows| {
+        windows
+            .iter()
+            .find(|w| active.as_ref() == Some(w.window.upcast_ref()))
+            .or(windows.last())
+            .map(|w| w.sender.clone())
+    })
+}
+
+/// Open files, each in its own window
+///
+/// The first file may go to the active window if it's still empty and not
+/// loading anything, the others always get a new window.
+pub(crate) fn open_files(requests: Vec<OpenRequest>) {
+    let mut requests = requests.into_iter();
+    let Some(first) = requests.next() else {
+        return;
+    };
+
+    match target_window() {
+        Some(sender) => sender.emit(AppMsg::OpenFile(first)),
+        None => {
+            open_new_window(Some(first));
+        }
+    }
+
+    for request in requests {
+        open_new_window(Some(request));
+    }
+}
+
+/// Open `decoded` as an untitled document, in the active window if it's still
+/// empty and not loading anything or in a new one otherwise
+pub(crate) fn open_text(decoded: DecodedText, waiter: Option<gio::ApplicationCommandLine>) {
+    target_window()
+        .unwrap_or_else(|| open_new_window(None))
+        .emit(AppMsg::OpenText(decoded, waiter));
+}